@@ -0,0 +1,158 @@
+use rpassword;
+
+use std::{
+    env,
+    fs::File,
+    io::{
+        self,
+        Read,
+    },
+    path::PathBuf,
+};
+
+use client::FileID;
+
+pub static USAGE: &'static str = "\
+usage: client [--server <url>] [--profile <name>] [--cipher <name>] [--password-file <path>] <command>
+
+commands:
+    put <id> <path>         Encrypt a file and upload it
+    get <id> [-o <path>]    Download, verify and decrypt a file, to standard output by default
+    verify <id>             Download a file and verify it, without decrypting it
+    rm <id>                 Remove a file
+    root                    Print the client's root hash
+    status                  Compare the client's root hash with the server's
+
+The password is read from the file given with --password-file, the environment
+variable CLIENT_PASSWORD or a prompt, in that order. New files are encrypted with
+the cipher given with --cipher or CLIENT_CIPHER: aes-128-gcm, aes-256-gcm or
+xchacha20-poly1305 (the default).";
+
+pub enum Command {
+    Put {
+        file: FileID,
+        path: PathBuf,
+    },
+
+    Get {
+        file: FileID,
+        output: Option<PathBuf>,
+    },
+
+    Verify {
+        file: FileID,
+    },
+
+    Remove {
+        file: FileID,
+    },
+
+    Root,
+
+    Status,
+}
+
+/// The command to run, and where to find the password
+pub struct Options {
+    pub command: Command,
+    pub password_file: Option<PathBuf>,
+}
+
+impl Options {
+    /// Parse the arguments left once the server's configuration has been taken out of them
+    pub fn parse(args: Vec<String>) -> Result<Options, String> {
+        let mut password_file = None;
+        let mut output = None;
+        let mut positional = Vec::new();
+
+        let mut args = args.into_iter();
+
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--password-file" => password_file = Some(flag_value(&arg, args.next())?),
+                "-o" | "--output" => output = Some(flag_value(&arg, args.next())?),
+                flag if flag.starts_with('-') && flag != "-" => return Err(format!("unknown flag {}", flag)),
+                _ => positional.push(arg)
+            }
+        }
+
+        let command = match positional.split_first() {
+            Some((name, rest)) => match (name.as_str(), rest) {
+                ("put", &[ref file, ref path]) => Command::Put {
+                    file: parse_id(file)?,
+                    path: PathBuf::from(path),
+                },
+
+                ("get", &[ref file]) => Command::Get {
+                    file: parse_id(file)?,
+                    output: output.take(),
+                },
+
+                ("verify", &[ref file]) => Command::Verify { file: parse_id(file)? },
+                ("rm", &[ref file]) => Command::Remove { file: parse_id(file)? },
+                ("root", &[]) => Command::Root,
+                ("status", &[]) => Command::Status,
+
+                (name, _) => return Err(format!("unknown command or wrong arguments: {}", name))
+            },
+
+            None => return Err("no command given".to_owned())
+        };
+
+        if output.is_some() {
+            return Err("-o is only used with get".to_owned());
+        }
+
+        Ok(Options {
+            command,
+            password_file,
+        })
+    }
+
+    /// Returns true if the command encrypts or decrypts files, and so needs a password
+    pub fn needs_password(&self) -> bool {
+        match self.command {
+            Command::Put { .. } | Command::Get { .. } => true,
+            _ => false
+        }
+    }
+
+    /// Read the password from the password file, `CLIENT_PASSWORD` or a prompt
+    pub fn read_password(&self) -> io::Result<Vec<u8>> {
+        if let Some(ref path) = self.password_file {
+            let mut password = Vec::new();
+            File::open(path)?.read_to_end(&mut password)?;
+
+            // A file written by an editor ends with a newline, which isn't part of the password
+            if password.ends_with(b"\n") {
+                password.pop();
+            }
+
+            if password.ends_with(b"\r") {
+                password.pop();
+            }
+
+            return Ok(password);
+        }
+
+        if let Some(password) = env::var_os("CLIENT_PASSWORD") {
+            return password.into_string()
+                .map(String::into_bytes)
+                .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "CLIENT_PASSWORD is not valid UTF-8"));
+        }
+
+        rpassword::prompt_password_stderr("Password: ")
+            .map(String::into_bytes)
+    }
+}
+
+fn flag_value<T: From<String>>(flag: &str, value: Option<String>) -> Result<T, String> {
+    value
+        .map(T::from)
+        .ok_or(format!("{} needs a value", flag))
+}
+
+fn parse_id(id: &str) -> Result<FileID, String> {
+    id.parse()
+        .map_err(|_| format!("invalid file ID: {}", id))
+}
@@ -1,35 +1,240 @@
-extern crate reqwest;
-extern crate ring;
+extern crate client;
+extern crate rpassword;
+extern crate tempfile;
 
-extern crate file_hash;
+mod cli;
 
-mod communication;
+use cli::{
+    Command,
+    Options,
+    USAGE
+};
+
+use client::{
+    Config,
+    Error,
+    FileID,
+    SecretError,
+    StorageClient,
+    VerificationError
+};
 
-use communication::{
-    upload_file,
-    download_file
+use tempfile::NamedTempFile;
+
+use std::{
+    env,
+    fs::File,
+    io,
+    path::Path,
+    process
 };
 
 
+/// The arguments could not be understood
+const EXIT_USAGE: i32 = 2;
+
+/// The server's profile could not be opened
+const EXIT_CONFIG: i32 = 3;
+
+/// A local file, or the password, could not be read or written
+const EXIT_IO: i32 = 4;
+
+/// A request to the server failed
+const EXIT_COMMUNICATION: i32 = 5;
+
+/// Other writers kept changing the server's tree
+const EXIT_TOO_MANY_ATTEMPTS: i32 = 6;
+
+/// The server proved that the file does not exist
+const EXIT_NOT_FOUND: i32 = 7;
+
+
 fn main() {
-    let client = reqwest::Client::new();
+    // The server is set with `--server <url>` or `CLIENT_SERVER`, and the profile with
+    // `--profile <name>` or `CLIENT_PROFILE`
+    let (config, args) = match Config::from_args(env::args().skip(1)) {
+        Ok(parsed) => parsed,
+        Err(e) => exit(EXIT_USAGE, &format!("{}\n\n{}", e, USAGE))
+    };
 
-    let password = b"abc";
+    let options = match Options::parse(args) {
+        Ok(options) => options,
+        Err(e) => exit(EXIT_USAGE, &format!("{}\n\n{}", e, USAGE))
+    };
 
-    use std::str::from_utf8 as s;
+    // Fails unless the server is one this client is able to verify
+    let client = match StorageClient::new(config) {
+        Ok(client) => client,
+        Err(error) => exit(exit_code(&error), &error.to_string())
+    };
 
-    {
-        let message = b"Super secret message";
-        println!("Uploading message: {:?}", s(message));
-        upload_file(message, 1342, password, &client);
-    }
+    let password = if options.needs_password() {
+        match options.read_password() {
+            Ok(password) => password,
+            Err(e) => exit(EXIT_IO, &format!("Failed to read the password: {}", e))
+        }
+    } else {
+        Vec::new()
+    };
+
+    let code = match run(&options.command, &password, &client) {
+        Ok(code) => code,
+        Err(error) => {
+            eprintln!("client: {}", error);
+            exit_code(&error)
+        }
+    };
+
+    process::exit(code)
+}
+
+
+/// Run a command, returning the code to exit with
+fn run(command: &Command, password: &[u8], client: &StorageClient) -> Result<i32, Error> {
+    match *command {
+        Command::Put { file, ref path } => {
+            let mut input = File::open(path)?;
+
+            client.synchronize()?;
+            client.upload(file, password, &mut input)?;
+
+            Ok(0)
+        }
+
+        Command::Get { file, ref output } => {
+            client.synchronize()?;
+
+            let found = match *output {
+                Some(ref path) => {
+                    // Written next to the path, and only moved over it once the whole file
+                    // has been verified and decrypted, so a failed download never replaces
+                    // or truncates what is already there. Otherwise it is removed when dropped.
+                    let directory = match path.parent() {
+                        Some(parent) if parent != Path::new("") => parent,
+                        _ => Path::new(".")
+                    };
 
-    {
-        let file = download_file(1342, password, &client);
-        println!("Downloaded message: {:?}", s(&file));
+                    let mut output = NamedTempFile::new_in(directory)?;
+                    let found = client.download(file, password, &mut output)?;
+
+                    if found {
+                        output.as_file().sync_all()?;
+                        output.persist(path).map_err(|e| e.error)?;
+                    }
+
+                    found
+                }
+
+                None => {
+                    let stdout = io::stdout();
+                    let mut stdout = stdout.lock();
+                    client.download(file, password, &mut stdout)?
+                }
+            };
+
+            Ok(if found { 0 } else { not_found(file) })
+        }
+
+        Command::Verify { file } => {
+            client.synchronize()?;
+
+            if client.verify(file)? {
+                println!("File {} is intact", file);
+                Ok(0)
+            } else {
+                Ok(not_found(file))
+            }
+        }
+
+        Command::Remove { file } => {
+            client.synchronize()?;
+
+            if client.delete(file)? {
+                Ok(0)
+            } else {
+                Ok(not_found(file))
+            }
+        }
+
+        Command::Root => {
+            let (client_root_hash, _) = client.root_hashes()?;
+            println!("{}", client_root_hash.to_hex());
+            Ok(0)
+        }
+
+        Command::Status => {
+            let (client_root_hash, server_root_hash) = client.root_hashes()?;
+
+            println!("Server:      {}", client.url());
+            println!("Profile:     {}", client.profile());
+            println!("Protocol:    version {}, features: {}", client.info().version, client.info().features.join(", "));
+            println!("Device key:  {}", client.device_key()?);
+            println!("Client root: {}", client_root_hash.to_hex());
+            println!("Server root: {}", server_root_hash.to_hex());
+
+            if client_root_hash == server_root_hash {
+                println!("Up to date");
+            } else {
+                println!("The server has changed since this client last synchronized");
+            }
+
+            Ok(0)
+        }
     }
 }
 
 
+fn not_found(file: FileID) -> i32 {
+    eprintln!("client: the server proved that file {} does not exist", file);
+    EXIT_NOT_FOUND
+}
+
+
+fn exit(code: i32, message: &str) -> ! {
+    eprintln!("client: {}", message);
+    process::exit(code)
+}
+
+
+/// Returns a distinct exit code for every kind of failure. Failures to verify the server
+/// start at 10, and failures to encrypt or decrypt at 40.
+fn exit_code(error: &Error) -> i32 {
+    match *error {
+        Error::Config(_) => EXIT_CONFIG,
+        Error::Communication(_) => EXIT_COMMUNICATION,
+        Error::Io(_) => EXIT_IO,
+        Error::TooManyAttempts => EXIT_TOO_MANY_ATTEMPTS,
 
+        Error::Verification(ref error) => match *error {
+            VerificationError::ClientHashNotFound(_) => 10,
+            VerificationError::ClientHashInvalid(_) => 11,
+            VerificationError::ClientHashNoWrite(_) => 12,
+            VerificationError::ServerHashNotFound(_) => 13,
+            VerificationError::ServerFormatNotFound(_) => 14,
+            VerificationError::UnknownTreeFormat(_) => 15,
+            VerificationError::ServerHashDependenciesNotFound(_) => 16,
+            VerificationError::InvalidProof(_) => 17,
+            VerificationError::ProofMismatch => 18,
+            VerificationError::HashOutOfDate { .. } => 19,
+            VerificationError::TamperedFiles => 20,
+            VerificationError::InconsistentHistory => 21,
+            VerificationError::InsecureTreeFormat(_) => 22,
+            VerificationError::ChunksUnsupported(_) => 23,
+            VerificationError::DeviceKeyNotFound(_) => 24,
+            VerificationError::UntrustedChange { .. } => 25,
+            VerificationError::ServerInfoNotFound(_) => 26,
+            VerificationError::IncompatibleServer(_) => 27,
+        },
 
+        Error::Secret(ref error) => match *error {
+            SecretError::AuthenticationFailed => 40,
+            SecretError::InvalidLength => 41,
+            SecretError::InvalidHeader => 42,
+            SecretError::UnknownVersion(_) => 43,
+            SecretError::UnknownCipher(_) => 44,
+            SecretError::UnknownKdf(_) => 45,
+            SecretError::Io(_) => 46,
+            SecretError::NotChunked => 47,
+        },
+    }
+}
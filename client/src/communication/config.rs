@@ -0,0 +1,335 @@
+use reqwest::Client;
+
+use protocol::routes;
+
+use super::envelope::Cipher;
+use super::secret::Options;
+
+use std::{
+    self,
+    env,
+    fmt,
+    fs::{
+        self,
+        DirBuilder,
+        File,
+    },
+    io::{
+        self,
+        Read,
+        Write,
+    },
+    path::{
+        Path,
+        PathBuf,
+    },
+};
+
+#[cfg(unix)]
+use std::os::unix::fs::DirBuilderExt;
+
+/// The server used unless another one is given
+pub static DEFAULT_SERVER: &'static str = "http://localhost:8000";
+
+/// The directory holding the client's files, within the user's configuration directory
+static CLIENT_DIRECTORY: &'static str = "file-storage";
+
+/// The directory holding a directory for every profile, within the client's directory
+static PROFILES_PATH: &'static str = "profiles";
+
+/// Path to the URL of the server a profile belongs to, within the profile
+static SERVER_PATH: &'static str = "server";
+
+/// Where the root hash was saved before the client had profiles. It is moved into the
+/// profile of the default server the first time that profile is used.
+static LEGACY_ROOT_HASH_PATH: &'static str = "root_hash";
+
+#[derive(Debug)]
+pub enum Error {
+    /// The profile could not be read or created
+    Io(io::Error),
+
+    /// A flag was given without a value
+    MissingValue(String),
+
+    /// A profile name can't be used as the name of a directory
+    InvalidProfile(String),
+
+    /// The name of a cipher this client does not support
+    UnknownCipher(String),
+
+    /// The profile belongs to another server
+    ProfileMismatch {
+        profile: String,
+        server: String,
+    },
+}
+
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Io(ref e) => write!(f, "could not open the profile: {}", e),
+            Error::MissingValue(ref flag) => write!(f, "{} needs a value", flag),
+            Error::InvalidProfile(ref profile) => write!(f, "\"{}\" can't be used as the name of a profile", profile),
+            Error::UnknownCipher(ref cipher) =>
+                write!(f, "unknown cipher {}, expected aes-128-gcm, aes-256-gcm or xchacha20-poly1305", cipher),
+            Error::ProfileMismatch { ref profile, ref server } =>
+                write!(f, "the profile {} belongs to the server {}", profile, server),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(std::error::Error + 'static)> {
+        match *self {
+            Error::Io(ref e) => Some(e),
+            _ => None
+        }
+    }
+}
+
+/// Which server the client talks to, the name of the profile where it keeps what
+/// it knows about that server, such as its root hash, and how it encrypts new files.
+#[derive(Clone, Debug)]
+pub struct Config {
+    /// The URL of the server, such as `http://localhost:8000`
+    pub server: String,
+
+    /// The name of the profile. Defaults to a name made from the server's URL, so every
+    /// server gets a profile of its own.
+    pub profile: Option<String>,
+
+    /// The cipher new files are encrypted with. Files are always decrypted with the
+    /// cipher recorded in them.
+    pub cipher: Cipher,
+}
+
+/// A server the client talks to, the directory of the profile it keeps for it and
+/// the cipher files uploaded to it are encrypted with
+pub struct Server {
+    client: Client,
+    url: String,
+    profile: PathBuf,
+    cipher: Cipher,
+}
+
+impl Config {
+    /// Read the configuration from the environment variables `CLIENT_SERVER`,
+    /// `CLIENT_PROFILE` and `CLIENT_CIPHER`, falling back to the defaults
+    pub fn from_env() -> Result<Config, Error> {
+        let cipher = match env::var("CLIENT_CIPHER") {
+            Ok(name) => parse_cipher(&name)?,
+            Err(_) => Options::default().cipher
+        };
+
+        Ok(Config {
+            server: env::var("CLIENT_SERVER").unwrap_or(DEFAULT_SERVER.to_owned()),
+            profile: env::var("CLIENT_PROFILE").ok(),
+            cipher,
+        })
+    }
+
+    /// Read the configuration like `from_env`, letting the flags `--server <url>`,
+    /// `--profile <name>` and `--cipher <name>` override it. Returns the configuration
+    /// and all other arguments.
+    pub fn from_args<I: Iterator<Item = String>>(args: I) -> Result<(Config, Vec<String>), Error> {
+        let mut config = Config::from_env()?;
+        let mut rest = Vec::new();
+        let mut args = args;
+
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--server" => {
+                    config.server = args.next().ok_or(Error::MissingValue(arg.clone()))?;
+                }
+
+                "--profile" => {
+                    config.profile = Some(args.next().ok_or(Error::MissingValue(arg.clone()))?);
+                }
+
+                "--cipher" => {
+                    config.cipher = parse_cipher(&args.next().ok_or(Error::MissingValue(arg.clone()))?)?;
+                }
+
+                _ => rest.push(arg)
+            }
+        }
+
+        Ok((config, rest))
+    }
+
+    /// Returns the name of the profile, made from the server's URL unless one was given
+    pub fn profile_name(&self) -> String {
+        match self.profile {
+            Some(ref profile) => profile.clone(),
+
+            None => {
+                let url = normalize(&self.server);
+                let url = url.splitn(2, "://").last().unwrap_or(&url);
+
+                url.chars()
+                    .map(|c| if c.is_ascii_alphanumeric() || c == '-' || c == '.' { c } else { '_' })
+                    .collect()
+            }
+        }
+    }
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            server: DEFAULT_SERVER.to_owned(),
+            profile: None,
+            cipher: Options::default().cipher,
+        }
+    }
+}
+
+impl Server {
+    /// Open the profile of a server, creating it if needed. A profile is only ever used
+    /// with the server it was created for.
+    pub fn new(config: &Config) -> Result<Server, Error> {
+        let url = normalize(&config.server);
+        let name = config.profile_name();
+
+        if name.is_empty() || name.starts_with('.') || name.contains('/') || name.contains('\\') {
+            return Err(Error::InvalidProfile(name));
+        }
+
+        let profile = client_path(PROFILES_PATH).map_err(Error::Io)?.join(&name);
+        fs::create_dir_all(&profile).map_err(Error::Io)?;
+
+        match File::open(profile.join(SERVER_PATH)) {
+            Ok(mut file) => {
+                let mut server = String::new();
+                file.read_to_string(&mut server).map_err(Error::Io)?;
+
+                if server != url {
+                    return Err(Error::ProfileMismatch {
+                        profile: name,
+                        server,
+                    });
+                }
+            }
+
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                File::create(profile.join(SERVER_PATH))
+                    .and_then(|mut file| file.write_all(url.as_bytes()))
+                    .map_err(Error::Io)?;
+
+                if url == DEFAULT_SERVER && Path::new(LEGACY_ROOT_HASH_PATH).exists() {
+                    relocate(Path::new(LEGACY_ROOT_HASH_PATH), &profile.join(LEGACY_ROOT_HASH_PATH))
+                        .map_err(Error::Io)?;
+                }
+            }
+
+            Err(e) => return Err(Error::Io(e))
+        }
+
+        Ok(Server {
+            client: Client::new(),
+            url,
+            profile,
+            cipher: config.cipher,
+        })
+    }
+
+    pub fn client(&self) -> &Client {
+        &self.client
+    }
+
+    /// Returns the URL of the server
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    /// Get the URI to a route of the server, such as `/verify/root`
+    pub fn uri(&self, route: &str) -> String {
+        format!("{}{}{}", self.url, routes::MOUNT, route)
+    }
+
+    /// Returns the cipher new files are encrypted with
+    pub fn cipher(&self) -> Cipher {
+        self.cipher
+    }
+
+    /// Get the path to a file in the server's profile
+    pub fn profile_path(&self, file: &str) -> PathBuf {
+        self.profile.join(file)
+    }
+}
+
+/// The directory the client keeps its files in: `CLIENT_HOME` if it is set, or else
+/// `file-storage` in the user's configuration directory
+pub fn client_directory() -> PathBuf {
+    if let Some(home) = env::var_os("CLIENT_HOME") {
+        return PathBuf::from(home);
+    }
+
+    let config = env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".config")))
+        .or_else(|| env::var_os("APPDATA").map(PathBuf::from));
+
+    match config {
+        Some(config) => config.join(CLIENT_DIRECTORY),
+        None => PathBuf::from(".")
+    }
+}
+
+/// Get the path to a file in the client's directory, creating the directory if needed.
+/// The directory is created readable only by its owner.
+pub fn client_path(file: &str) -> io::Result<PathBuf> {
+    let directory = client_directory();
+
+    let mut builder = DirBuilder::new();
+    builder.recursive(true);
+
+    #[cfg(unix)]
+    builder.mode(0o700);
+
+    builder.create(&directory)?;
+
+    Ok(directory.join(file))
+}
+
+/// Move a file, copying it if it is on another file system
+fn relocate(from: &Path, to: &Path) -> io::Result<()> {
+    if fs::rename(from, to).is_ok() {
+        return Ok(());
+    }
+
+    fs::copy(from, to)?;
+    fs::remove_file(from)
+}
+
+/// Parse the name of a cipher: `aes-128-gcm`, `aes-256-gcm` or `xchacha20-poly1305`
+fn parse_cipher(name: &str) -> Result<Cipher, Error> {
+    Cipher::from_name(name).ok_or(Error::UnknownCipher(name.to_owned()))
+}
+
+/// Remove trailing slashes from a URL, so that the same server always gets the same URL
+fn normalize(url: &str) -> String {
+    url.trim().trim_end_matches('/').to_owned()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<(Config, Vec<String>), Error> {
+        Config::from_args(args.iter().map(|arg| arg.to_string()))
+    }
+
+    #[test]
+    fn choose_a_cipher() {
+        let (config, rest) = parse(&["--cipher", "aes-256-gcm", "get", "1"]).unwrap();
+        assert_eq!(Cipher::Aes256Gcm, config.cipher);
+        assert_eq!(vec!["get", "1"], rest);
+
+        assert!(match parse(&["--cipher", "rot13"]) { Err(Error::UnknownCipher(_)) => true, _ => false });
+        assert!(match parse(&["--cipher"]) { Err(Error::MissingValue(_)) => true, _ => false });
+    }
+}
@@ -0,0 +1,229 @@
+use ring::{
+    rand::SystemRandom,
+    signature::{
+        self,
+        Ed25519KeyPair,
+    },
+};
+
+use untrusted::Input;
+
+use std::{
+    self,
+    fmt,
+    fs::{
+        self,
+        File,
+        OpenOptions,
+    },
+    io::{
+        self,
+        Read,
+        Write,
+    },
+    path::Path,
+};
+
+#[cfg(unix)]
+use std::os::unix::fs::OpenOptionsExt;
+
+use super::config::client_path;
+
+use protocol::{
+    Hash,
+    from_hex,
+    to_big_endian,
+};
+
+/// Path to this device's Ed25519 key pair, in PKCS#8, within the client's directory.
+/// Created the first time a change is signed, readable only by its owner.
+static DEVICE_KEY_PATH: &'static str = "device_key";
+
+/// Path to the public keys of other devices whose changes are accepted, one per line in
+/// hexadecimal, within the client's directory
+static TRUSTED_DEVICES_PATH: &'static str = "trusted_devices";
+
+/// Separates signatures of changes from anything else signed with the same key
+static CHANGE_CONTEXT: &'static [u8] = b"file storage change";
+
+static PUBLIC_KEY_LEN: usize = 32;
+
+#[derive(Debug)]
+pub enum Error {
+    /// The device's key or the list of trusted devices could not be read or written
+    Io(io::Error),
+
+    /// The device's key is not a valid Ed25519 key pair
+    InvalidKey,
+
+    /// A line of the list of trusted devices is not a public key
+    InvalidTrustedDevice(String),
+}
+
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Io(ref e) => write!(f, "could not read or write the device's key or trusted devices: {}", e),
+            Error::InvalidKey => write!(f, "the device's key is not a valid Ed25519 key pair"),
+            Error::InvalidTrustedDevice(ref line) => write!(f, "\"{}\" in the trusted devices is not a public key", line),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(std::error::Error + 'static)> {
+        match *self {
+            Error::Io(ref e) => Some(e),
+            _ => None
+        }
+    }
+}
+
+/// The key this device signs its changes with, and the public keys of the devices it trusts.
+///
+/// A signature is the signer's public key followed by the Ed25519 signature of the
+/// root hash before the change, the file ID as a big-endian 64-bit integer and the
+/// leaf after the change. Binding the root hash before the change means a signature
+/// can't be replayed elsewhere in the history.
+pub struct Devices {
+    key: Ed25519KeyPair,
+    trusted: Vec<Vec<u8>>,
+}
+
+impl Devices {
+    /// Load this device's key, creating it if needed, and the list of trusted devices
+    pub fn load() -> Result<Devices, Error> {
+        Ok(Devices {
+            key: load_key()?,
+            trusted: load_trusted()?,
+        })
+    }
+
+    /// Returns this device's public key
+    pub fn public_key(&self) -> &[u8] {
+        self.key.public_key_bytes()
+    }
+
+    /// Sign a change made to the file at `index`, from a tree with the root hash `before`
+    pub fn sign_change(&self, before: &Hash, index: u64, leaf: &Hash) -> Vec<u8> {
+        let mut bytes = self.public_key().to_vec();
+        bytes.extend_from_slice(self.key.sign(&change_message(before, index, leaf)).as_ref());
+        bytes
+    }
+
+    /// Returns true if a change was signed by this device or a trusted one
+    pub fn is_trusted(&self, signature: &[u8], before: &Hash, index: u64, leaf: &Hash) -> bool {
+        if signature.len() <= PUBLIC_KEY_LEN {
+            return false;
+        }
+
+        let (public_key, signature) = signature.split_at(PUBLIC_KEY_LEN);
+
+        let known = public_key == self.public_key() ||
+            self.trusted.iter().any(|trusted| &trusted[..] == public_key);
+
+        known && signature::verify(
+            &signature::ED25519,
+            Input::from(public_key),
+            Input::from(&change_message(before, index, leaf)),
+            Input::from(signature)
+        ).is_ok()
+    }
+}
+
+/// The message signed for a change
+fn change_message(before: &Hash, index: u64, leaf: &Hash) -> Vec<u8> {
+    let mut message = CHANGE_CONTEXT.to_vec();
+    message.extend_from_slice(before.as_bytes());
+    message.extend(to_big_endian(index, 8));
+    message.extend_from_slice(leaf.as_bytes());
+    message
+}
+
+fn load_key() -> Result<Ed25519KeyPair, Error> {
+    let path = client_path(DEVICE_KEY_PATH).map_err(Error::Io)?;
+
+    let pkcs8 = match File::open(&path) {
+        Ok(mut file) => {
+            let mut bytes = Vec::new();
+            file.read_to_end(&mut bytes).map_err(Error::Io)?;
+            bytes
+        }
+
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+            let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new())
+                .map_err(|_| Error::InvalidKey)?;
+
+            // Never replace a key created in the meantime, and don't leave half a key behind
+            let mut file = create_private(&path).map_err(Error::Io)?;
+
+            if let Err(e) = file.write_all(&pkcs8[..]).and_then(|_| file.sync_all()) {
+                let _ = fs::remove_file(&path);
+                return Err(Error::Io(e));
+            }
+
+            pkcs8[..].to_vec()
+        }
+
+        Err(e) => return Err(Error::Io(e))
+    };
+
+    Ed25519KeyPair::from_pkcs8(Input::from(&pkcs8))
+        .map_err(|_| Error::InvalidKey)
+}
+
+/// Create a new file only its owner can read or write, failing if it already exists
+fn create_private(path: &Path) -> io::Result<File> {
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+
+    #[cfg(unix)]
+    options.mode(0o600);
+
+    options.open(path)
+}
+
+fn load_trusted() -> Result<Vec<Vec<u8>>, Error> {
+    let mut text = String::new();
+
+    match File::open(client_path(TRUSTED_DEVICES_PATH).map_err(Error::Io)?) {
+        Ok(mut file) => file.read_to_string(&mut text).map_err(Error::Io)?,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(Error::Io(e))
+    };
+
+    text.lines()
+        .map(|line| line.trim())
+        .filter(|line| !line.is_empty())
+        .map(|line| match from_hex(line) {
+            Some(ref key) if key.len() == PUBLIC_KEY_LEN => Ok(key.clone()),
+            _ => Err(Error::InvalidTrustedDevice(line.to_owned()))
+        })
+        .collect()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tempfile::tempdir;
+
+    #[test]
+    fn create_private_files_once() {
+        let directory = tempdir().unwrap();
+        let path = directory.path().join(DEVICE_KEY_PATH);
+
+        create_private(&path).unwrap().write_all(b"key").unwrap();
+
+        let e = create_private(&path).err().unwrap();
+        assert_eq!(io::ErrorKind::AlreadyExists, e.kind());
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            assert_eq!(0o600, fs::metadata(&path).unwrap().permissions().mode() & 0o777);
+        }
+    }
+}
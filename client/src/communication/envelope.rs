@@ -0,0 +1,461 @@
+//! The header stored in front of every encrypted file, describing how it was
+//! encrypted.
+//!
+//! All integers are big-endian:
+//!
+//! | Bytes | Content |
+//! | --- | --- |
+//! | 4 | Magic number, `SCRT` |
+//! | 1 | Version of the envelope |
+//! | 1 | ID of the cipher |
+//! | 1 | ID of the key derivation function |
+//! | 2 | Length of the KDF parameters |
+//! | varies | KDF parameters |
+//! | 1 | Length of the salt |
+//! | varies | Salt |
+//! | 1 | Length of the nonce |
+//! | varies | Nonce, or the prefix of every chunk's nonce in version 3 |
+//! | 4 | Size of a chunk, before encryption (only in version 3) |
+//!
+//! The encrypted data follows the header. The whole header is authenticated
+//! together with the file's verification data, so it can't be changed without
+//! the file failing to decrypt.
+//!
+//! In version 2 the whole file is encrypted as a single message. In version 3
+//! it is split into chunks which are encrypted one at a time, so that files of
+//! any size can be encrypted and decrypted in constant memory.
+
+use ring::{
+    aead::{
+        self,
+        AES_128_GCM,
+        AES_256_GCM,
+    },
+    pbkdf2,
+    digest,
+};
+
+use argon2::{
+    self,
+    Config,
+    ThreadMode,
+    Variant,
+    Version,
+};
+
+use std::io::{
+    self,
+    Read,
+};
+
+use protocol::{
+    from_big_endian,
+    to_big_endian,
+};
+
+use super::secret::Error;
+
+
+pub static MAGIC: &'static [u8; 4] = b"SCRT";
+
+/// The first version of the envelope, which encrypts the whole file at once
+pub static SINGLE_VERSION: u8 = 2;
+
+/// The version of the envelope written by this client, which encrypts the
+/// file in chunks
+pub static VERSION: u8 = 3;
+
+/// The number of bytes at the end of a chunk's nonce holding the index of the
+/// chunk and whether it's the last one
+pub static NONCE_SUFFIX_LEN: usize = 5;
+
+/// The largest chunks a file may use, so that a malicious header can't make
+/// the client allocate too much memory
+static MAX_CHUNK_SIZE: u32 = 16 * 1024 * 1024;
+
+
+/// An algorithm used to encrypt and authenticate files
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Cipher {
+    Aes128Gcm,
+    Aes256Gcm,
+
+    /// ChaCha20-Poly1305 with a 192-bit nonce, which is large enough to be
+    /// chosen at random for any number of files
+    XChaCha20Poly1305,
+}
+
+/// A function deriving keys from passwords
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Kdf {
+    /// PBKDF2 with HMAC-SHA256
+    Pbkdf2 {
+        iterations: u32
+    },
+
+    /// Argon2id, which needs a lot of memory and is therefore expensive to
+    /// run on GPUs
+    Argon2id {
+        /// Memory used, in KiB
+        memory: u32,
+
+        /// Number of passes over the memory
+        time: u32,
+
+        /// Number of lanes computed in parallel
+        parallelism: u32
+    },
+}
+
+
+/// Describes how a file was encrypted
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Header {
+    /// The version of the format the file was stored in. Files stored before
+    /// the envelope existed have version 0 or 1, and no header of their own.
+    pub version: u8,
+
+    pub cipher: Cipher,
+    pub kdf: Kdf,
+    pub salt: Vec<u8>,
+    pub nonce: Vec<u8>,
+
+    /// The number of bytes in every chunk but the last, or `None` if the file
+    /// is encrypted as a single message
+    pub chunk_size: Option<u32>,
+}
+
+
+impl Cipher {
+    pub fn from_id(id: u8) -> Option<Cipher> {
+        match id {
+            1 => Some(Cipher::Aes128Gcm),
+            2 => Some(Cipher::Aes256Gcm),
+            3 => Some(Cipher::XChaCha20Poly1305),
+            _ => None
+        }
+    }
+
+    pub fn id(&self) -> u8 {
+        match self {
+            &Cipher::Aes128Gcm => 1,
+            &Cipher::Aes256Gcm => 2,
+            &Cipher::XChaCha20Poly1305 => 3,
+        }
+    }
+
+
+    /// Parse the name of a cipher, as used in the configuration
+    pub fn from_name(name: &str) -> Option<Cipher> {
+        match name {
+            "aes-128-gcm" => Some(Cipher::Aes128Gcm),
+            "aes-256-gcm" => Some(Cipher::Aes256Gcm),
+            "xchacha20-poly1305" => Some(Cipher::XChaCha20Poly1305),
+            _ => None
+        }
+    }
+
+
+    /// The algorithm used by ring, if ring implements the cipher
+    pub fn ring_algorithm(&self) -> Option<&'static aead::Algorithm> {
+        match self {
+            &Cipher::Aes128Gcm => Some(&AES_128_GCM),
+            &Cipher::Aes256Gcm => Some(&AES_256_GCM),
+            &Cipher::XChaCha20Poly1305 => None,
+        }
+    }
+
+    pub fn key_len(&self) -> usize {
+        match self.ring_algorithm() {
+            Some(algorithm) => algorithm.key_len(),
+            None => 32,
+        }
+    }
+
+    pub fn nonce_len(&self) -> usize {
+        match self.ring_algorithm() {
+            Some(algorithm) => algorithm.nonce_len(),
+            None => 24,
+        }
+    }
+
+    pub fn tag_len(&self) -> usize {
+        match self.ring_algorithm() {
+            Some(algorithm) => algorithm.tag_len(),
+            None => 16,
+        }
+    }
+}
+
+
+impl Kdf {
+    /// The costs of Argon2id used for new files: 64 MiB of memory, three
+    /// passes and four lanes
+    pub const DEFAULT_ARGON2ID: Kdf = Kdf::Argon2id {
+        memory: 64 * 1024,
+        time: 3,
+        parallelism: 4
+    };
+
+    /// The most memory a file may ask for, in KiB, so that a malicious header
+    /// can't exhaust the client's memory: 1 GiB, far more than the client ever
+    /// uses for its own files
+    const MAX_ARGON2ID_MEMORY: u32 = 1024 * 1024;
+
+    /// The most passes a file may ask for, so that a malicious header can't
+    /// keep the client busy deriving a key
+    const MAX_ARGON2ID_TIME: u32 = 16;
+
+    /// The most lanes a file may ask for, each of which is a thread
+    const MAX_ARGON2ID_PARALLELISM: u32 = 16;
+
+    /// The most iterations of PBKDF2 a file may ask for
+    const MAX_PBKDF2_ITERATIONS: u32 = 10_000_000;
+
+
+    pub fn from_parameters(id: u8, parameters: &[u8]) -> Result<Kdf, Error> {
+        let values: Vec<u32> = parameters.chunks(4)
+            .map(|bytes| from_big_endian(bytes) as u32)
+            .collect();
+
+        match id {
+            1 => {
+                if parameters.len() != 4 || values[0] == 0 || values[0] > Kdf::MAX_PBKDF2_ITERATIONS {
+                    return Err(Error::InvalidHeader);
+                }
+
+                Ok(Kdf::Pbkdf2 {
+                    iterations: values[0]
+                })
+            }
+
+            2 => {
+                if parameters.len() != 12 {
+                    return Err(Error::InvalidHeader);
+                }
+
+                let (memory, time, parallelism) = (values[0], values[1], values[2]);
+
+                // The smallest costs Argon2 allows, and the largest this client accepts
+                let min_memory = match parallelism.checked_mul(8) {
+                    Some(min_memory) => min_memory,
+                    None => return Err(Error::InvalidHeader)
+                };
+
+                if time == 0 || time > Kdf::MAX_ARGON2ID_TIME
+                    || parallelism == 0 || parallelism > Kdf::MAX_ARGON2ID_PARALLELISM
+                    || memory < min_memory || memory > Kdf::MAX_ARGON2ID_MEMORY {
+                    return Err(Error::InvalidHeader);
+                }
+
+                Ok(Kdf::Argon2id {
+                    memory,
+                    time,
+                    parallelism
+                })
+            }
+
+            _ => Err(Error::UnknownKdf(id))
+        }
+    }
+
+    pub fn id(&self) -> u8 {
+        match self {
+            &Kdf::Pbkdf2 { .. } => 1,
+            &Kdf::Argon2id { .. } => 2,
+        }
+    }
+
+    pub fn parameters(&self) -> Vec<u8> {
+        let values = match self {
+            &Kdf::Pbkdf2 { iterations } => vec![iterations],
+            &Kdf::Argon2id { memory, time, parallelism } => vec![memory, time, parallelism],
+        };
+
+        values.iter()
+            .flat_map(|&value| to_big_endian(value as u64, 4))
+            .collect()
+    }
+
+
+    /// Derive a key, filling all of `key`, from a password and a salt
+    pub fn derive(&self, password: &[u8], salt: &[u8], key: &mut [u8]) -> Result<(), Error> {
+        match self {
+            &Kdf::Pbkdf2 { iterations } => {
+                pbkdf2::derive(&digest::SHA256, iterations, salt, password, key);
+                Ok(())
+            }
+
+            &Kdf::Argon2id { memory, time, parallelism } => {
+                let config = Config {
+                    variant: Variant::Argon2id,
+                    version: Version::Version13,
+                    mem_cost: memory,
+                    time_cost: time,
+                    lanes: parallelism,
+                    thread_mode: ThreadMode::from_threads(parallelism),
+                    secret: &[],
+                    ad: &[],
+                    hash_length: key.len() as u32
+                };
+
+                let hash = argon2::hash_raw(password, salt, &config)
+                    .map_err(|_| Error::InvalidHeader)?;
+
+                key.copy_from_slice(&hash);
+                Ok(())
+            }
+        }
+    }
+}
+
+
+impl Header {
+    /// Read a header from the start of an envelope, leaving the reader at the
+    /// encrypted data following it
+    pub fn read<R: Read>(reader: &mut R) -> Result<Header, Error> {
+        if &read_bytes(reader, MAGIC.len())?[..] != &MAGIC[..] {
+            return Err(Error::InvalidHeader);
+        }
+
+        let version = read_bytes(reader, 1)?[0];
+        if version != SINGLE_VERSION && version != VERSION {
+            return Err(Error::UnknownVersion(version));
+        }
+
+        let cipher_id = read_bytes(reader, 1)?[0];
+        let cipher = Cipher::from_id(cipher_id).ok_or(Error::UnknownCipher(cipher_id))?;
+
+        let kdf_id = read_bytes(reader, 1)?[0];
+        let parameters_len = read_integer(reader, 2)? as usize;
+        let kdf = Kdf::from_parameters(kdf_id, &read_bytes(reader, parameters_len)?)?;
+
+        let salt_len = read_bytes(reader, 1)?[0] as usize;
+        let salt = read_bytes(reader, salt_len)?;
+
+        let nonce_len = read_bytes(reader, 1)?[0] as usize;
+        let nonce = read_bytes(reader, nonce_len)?;
+
+        let chunk_size = if version == VERSION {
+            let chunk_size = read_integer(reader, 4)?;
+
+            if chunk_size == 0 || chunk_size > MAX_CHUNK_SIZE {
+                return Err(Error::InvalidHeader);
+            }
+
+            Some(chunk_size)
+        } else {
+            None
+        };
+
+        let expected_nonce_len = match chunk_size {
+            Some(_) => cipher.nonce_len() - NONCE_SUFFIX_LEN,
+            None => cipher.nonce_len()
+        };
+
+        if nonce.len() != expected_nonce_len {
+            return Err(Error::InvalidHeader);
+        }
+
+        Ok(Header {
+            version,
+            cipher,
+            kdf,
+            salt,
+            nonce,
+            chunk_size,
+        })
+    }
+
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let parameters = self.kdf.parameters();
+
+        let mut bytes = MAGIC.to_vec();
+        bytes.push(self.version);
+        bytes.push(self.cipher.id());
+        bytes.push(self.kdf.id());
+        bytes.extend(to_big_endian(parameters.len() as u64, 2));
+        bytes.extend_from_slice(&parameters);
+        bytes.push(self.salt.len() as u8);
+        bytes.extend_from_slice(&self.salt);
+        bytes.push(self.nonce.len() as u8);
+        bytes.extend_from_slice(&self.nonce);
+
+        if let Some(chunk_size) = self.chunk_size {
+            bytes.extend(to_big_endian(chunk_size as u64, 4));
+        }
+
+        bytes
+    }
+
+
+    /// The data authenticated along with the encrypted data
+    pub fn additional_data(&self, verification: &[u8]) -> Vec<u8> {
+        // Older formats only authenticated the verification data
+        let mut data = if self.version >= SINGLE_VERSION {
+            self.to_bytes()
+        } else {
+            Vec::new()
+        };
+
+        data.extend_from_slice(verification);
+        data
+    }
+}
+
+
+/// Read an exact number of bytes
+fn read_bytes<R: Read>(reader: &mut R, count: usize) -> Result<Vec<u8>, Error> {
+    let mut bytes = vec![0; count];
+
+    match reader.read_exact(&mut bytes) {
+        Ok(()) => Ok(bytes),
+        Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => Err(Error::InvalidLength),
+        Err(e) => Err(Error::Io(e))
+    }
+}
+
+/// Read a big-endian integer of some number of bytes
+fn read_integer<R: Read>(reader: &mut R, count: usize) -> Result<u32, Error> {
+    Ok(from_big_endian(&read_bytes(reader, count)?) as u32)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn argon2id(memory: u32, time: u32, parallelism: u32) -> Result<Kdf, Error> {
+        let parameters = Kdf::Argon2id { memory, time, parallelism }.parameters();
+        Kdf::from_parameters(2, &parameters)
+    }
+
+    #[test]
+    fn kdf_parameters_round_trip() {
+        for kdf in vec![Kdf::DEFAULT_ARGON2ID, Kdf::Pbkdf2 { iterations: 100_000 }] {
+            assert_eq!(kdf, Kdf::from_parameters(kdf.id(), &kdf.parameters()).unwrap());
+        }
+    }
+
+    #[test]
+    fn refuse_unreasonable_costs() {
+        assert!(argon2id(8, 1, 1).is_ok());
+        assert!(argon2id(7, 1, 1).is_err());
+        assert!(argon2id(1024, 0, 1).is_err());
+        assert!(argon2id(1024, Kdf::MAX_ARGON2ID_TIME + 1, 1).is_err());
+        assert!(argon2id(1024, 1, 0).is_err());
+        assert!(argon2id(1024, 1, Kdf::MAX_ARGON2ID_PARALLELISM + 1).is_err());
+        assert!(argon2id(Kdf::MAX_ARGON2ID_MEMORY, 1, 1).is_ok());
+        assert!(argon2id(Kdf::MAX_ARGON2ID_MEMORY + 1, 1, 1).is_err());
+        assert!(argon2id(4 * 1024 * 1024, 1, 1).is_err());
+
+        // 8 lanes of memory would overflow
+        assert!(argon2id(0, 1, 0x2000_0000).is_err());
+
+        let pbkdf2 = |iterations: u32| Kdf::from_parameters(1, &to_big_endian(iterations as u64, 4));
+        assert!(pbkdf2(1).is_ok());
+        assert!(pbkdf2(0).is_err());
+        assert!(pbkdf2(Kdf::MAX_PBKDF2_ITERATIONS + 1).is_err());
+    }
+}
@@ -1,104 +1,591 @@
 
-mod secret;
-mod verification;
+pub mod config;
+pub mod device;
+mod envelope;
+pub mod secret;
+pub mod verification;
 
 use reqwest::{
     self,
-    Client,
+    Body,
+    Response,
     StatusCode,
+    header::RANGE,
 };
 
-use std::io::Read;
+use tempfile;
 
-use self::secret::Secret;
+use error;
+
+use std::{
+    self,
+    fmt,
+    fs::File,
+    io::{
+        self,
+        Read,
+        Seek,
+        SeekFrom,
+        Write,
+    },
+};
+
+use file_hash::{
+    LeafChange,
+    LeafHasher,
+    CHUNK_SIZE
+};
+
+use protocol::{
+    self,
+    FileID,
+    Hash,
+    Info,
+    FILE_ID_BITS,
+    CHANGE_SIGNATURE_HEADER,
+    EXPECTED_ROOT_HEADER,
+    routes,
+    to_big_endian,
+};
+
+pub use self::config::{
+    Config,
+    Server,
+    DEFAULT_SERVER,
+};
+pub use self::envelope::Cipher;
+use self::secret::{
+    Secret,
+    Options,
+    RangeDecryptor,
+    encrypt_stream,
+    decrypt_stream,
+};
 use self::verification::{
+    leaf_hasher,
+    supports_chunk_proofs,
+    get_chunk_proof,
+    verify_chunks,
     verify_file,
+    verify_files,
+    verify_absent,
+    fast_forward_root_hash,
     compute_new_root_hash,
-    update_root_hash
+    compute_removed_root_hash,
+    sign_change,
+    sign_removal,
+    device_public_key,
+    update_root_hash,
+    verify_root_hash,
+    load_root_hash,
+    fetch_root_hash,
+    check_server_info
 };
 
 
+/// How many times a change is tried, if other writers keep changing the server's tree
+const MAX_ATTEMPTS: usize = 5;
+
 #[derive(Debug)]
 pub enum Error {
     Reqwest(reqwest::Error),
-    Http(StatusCode)
+    Http(StatusCode),
+
+    /// The server responded with something unexpected
+    InvalidResponse
+}
+
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Reqwest(ref e) => write!(f, "the request to the server failed: {}", e),
+            Error::Http(status) => write!(f, "the server responded with {}", status),
+            Error::InvalidResponse => write!(f, "the server responded with something unexpected"),
+        }
+    }
 }
 
-type FileID = u16;
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(std::error::Error + 'static)> {
+        match *self {
+            Error::Reqwest(ref e) => Some(e),
+            _ => None
+        }
+    }
+}
 
 
 /// Encrypt a file and upload it to the server
-pub fn upload_file(message: &[u8], file: FileID, password: &[u8], client: &Client) {
+pub fn upload_file(message: &[u8], file: FileID, password: &[u8], server: &Server) -> Result<(), error::Error> {
+    upload_stream(&mut &message[..], file, password, server)
+}
+
+
+/// Encrypt everything read from `reader` and upload it to the server as a file.
+///
+/// The encrypted file is staged in a temporary file, since it has to be hashed
+/// before it is uploaded, so files of any size are uploaded in constant memory.
+pub fn upload_stream<R: Read>(reader: &mut R, file: FileID, password: &[u8], server: &Server) -> Result<(), error::Error> {
     let verification = serialize_file_id(file);
 
-    let secret = Secret::new(password, message, &verification);
+    let mut encrypted = tempfile::tempfile()?;
 
-    let root_hash = compute_new_root_hash(client, file, &secret.as_bytes())
-        .expect("Failed to compute new root hash");
+    encrypt_stream(password, &verification, encryption_options(server), reader, &mut encrypted)?;
 
-    upload(client, &file_uri(file), secret.as_bytes())
-        .expect("Failed to upload file");
+    let hasher = leaf_hasher(server)?;
+    let leaf = hash_file(hasher, &mut encrypted)?;
+    let length = encrypted.seek(SeekFrom::End(0))?;
 
-    update_root_hash(client, root_hash)
-        .expect("Failed to update root hash");
+    let root_hash = change_tree(
+        server,
+        || compute_new_root_hash(server, file, &leaf),
+        |expected| {
+            let signature = sign_change(file, expected, &leaf)?;
 
-    verify_file(client, file, &secret.as_bytes())
-        .expect("File not stored correctly")
+            let mut body = encrypted.try_clone()?;
+            body.seek(SeekFrom::Start(0))?;
+
+            upload(server, &routes::file(file), expected, &signature, Body::sized(body, length))?;
+            Ok(())
+        }
+    )?;
+
+    update_root_hash(server, root_hash)?;
+
+    confirm_change(server, verify_file(server, file, &leaf))
+}
+
+
+/// Download, verify and decrypt a file from the server. Returns `None` if the
+/// server proves that the file does not exist.
+pub fn download_file(file: FileID, password: &[u8], server: &Server) -> Result<Option<Vec<u8>>, error::Error> {
+    let mut data = Vec::new();
+
+    if download_stream(file, password, server, &mut data)? {
+        Ok(Some(data))
+    } else {
+        Ok(None)
+    }
 }
 
 
-/// Download, verify and decrypt a file from the server
-pub fn download_file(file: FileID, password: &[u8], client: &Client) -> Vec<u8> {
+/// Download, verify and decrypt a file from the server, writing its contents to
+/// `writer`. Returns `false` if the server proves that the file does not exist.
+///
+/// The encrypted file is staged in a temporary file until it has been verified,
+/// so files of any size are downloaded in constant memory.
+pub fn download_stream<W: Write>(file: FileID, password: &[u8], server: &Server, writer: &mut W) -> Result<bool, error::Error> {
     let verification = serialize_file_id(file);
 
-    let bytes = download(client, &file_uri(file))
-        .expect("Failed to download file");
+    let mut encrypted = match download_verified(file, server)? {
+        Some(encrypted) => encrypted,
+        None => return Ok(false)
+    };
+
+    encrypted.seek(SeekFrom::Start(0))?;
+    decrypt_stream(password, &verification, &mut encrypted, writer)?;
+
+    Ok(true)
+}
+
+
+/// Download a file from the server and verify it, without decrypting it. Returns
+/// `false` if the server proves that the file does not exist.
+pub fn verify_stored_file(file: FileID, server: &Server) -> Result<bool, error::Error> {
+    download_verified(file, server)
+        .map(|encrypted| encrypted.is_some())
+}
+
+
+/// Download, verify and decrypt up to `length` bytes of a file, starting at `offset`.
+/// Only the chunks holding the bytes are downloaded, unless the file was stored in a
+/// format which can't be read in parts. Returns fewer bytes if the file ends before,
+/// and `None` if the server proves that the file does not exist.
+pub fn download_range(file: FileID, offset: u64, length: u64, password: &[u8], server: &Server) -> Result<Option<Vec<u8>>, error::Error> {
+    if !supports_chunk_proofs(server)? {
+        return download_range_of_whole_file(file, offset, length, password, server);
+    }
+
+    let verification = serialize_file_id(file);
+
+    // The first chunk holds the header of the envelope
+    let (stored_length, start) = match download_chunks(file, 0, 1, server) {
+        Ok(chunks) => chunks,
+
+        // The server has no chunks of a missing file
+        Err(error::Error::Verification(verification::Error::ServerHashDependenciesNotFound(Error::Http(StatusCode::NOT_FOUND)))) => {
+            verify_absent(server, file)?;
+            return Ok(None);
+        }
+
+        Err(e) => return Err(e)
+    };
+
+    let decryptor = match RangeDecryptor::new(password, &verification, &start, stored_length) {
+        Ok(decryptor) => decryptor,
+        Err(secret::Error::NotChunked) => {
+            return download_range_of_whole_file(file, offset, length, password, server);
+        }
+        Err(e) => return Err(error::Error::Secret(e))
+    };
+
+    if length == 0 || offset >= decryptor.len() {
+        return Ok(Some(Vec::new()));
+    }
+
+    let length = length.min(decryptor.len() - offset);
+    let (start, end) = decryptor.encrypted_range(offset, length);
+
+    let first = start / CHUNK_SIZE;
+    let last = (end - 1) / CHUNK_SIZE;
+    let (_, bytes) = download_chunks(file, first, last - first + 1, server)?;
+
+    let skipped = first * CHUNK_SIZE;
+
+    // The file may have been replaced by a shorter one since its first chunk was read
+    if end - skipped > bytes.len() as u64 {
+        return Err(error::Error::Communication(Error::InvalidResponse));
+    }
+
+    let encrypted = &bytes[(start - skipped) as usize..(end - skipped) as usize];
+
+    Ok(Some(decryptor.decrypt(offset, length, encrypted)?))
+}
+
+
+/// Download a run of chunks of a file, as stored on the server, and verify them without
+/// downloading the rest of the file. Returns the length of the whole file, and the chunks.
+pub fn download_chunks(file: FileID, first: u64, count: u64, server: &Server) -> Result<(u64, Vec<u8>), error::Error> {
+    let proof = get_chunk_proof(server, file, first, count)?;
+
+    let start = first * CHUNK_SIZE;
+    let end = proof.length().min((first + count) * CHUNK_SIZE);
+
+    // An empty file still has one, empty, chunk
+    let bytes = if start < end {
+        download_range_bytes(server, &routes::file(file), start, end)?
+    } else {
+        Vec::new()
+    };
+
+    {
+        let mut chunks: Vec<&[u8]> = bytes.chunks(CHUNK_SIZE as usize).collect();
+
+        if chunks.is_empty() {
+            chunks.push(&bytes);
+        }
+
+        verify_chunks(server, file, &proof, &chunks)?;
+    }
+
+    Ok((proof.length(), bytes))
+}
+
+
+/// Download a whole file, and return a range of it
+fn download_range_of_whole_file(file: FileID, offset: u64, length: u64, password: &[u8], server: &Server) -> Result<Option<Vec<u8>>, error::Error> {
+    let data = match download_file(file, password, server)? {
+        Some(data) => data,
+        None => return Ok(None)
+    };
+
+    let start = offset.min(data.len() as u64) as usize;
+    let end = offset.saturating_add(length).min(data.len() as u64) as usize;
+
+    Ok(Some(data[start..end].to_vec()))
+}
+
+
+/// Remove a file from the server. Returns `false` if the server proves that the
+/// file did not exist.
+pub fn delete_file(file: FileID, server: &Server) -> Result<bool, error::Error> {
+    let deleted = change_tree(
+        server,
+        || compute_removed_root_hash(server, file),
+        |expected| {
+            let signature = sign_removal(server, file, expected)?;
+
+            delete(server, &routes::file(file), expected, &signature)?;
+            Ok(())
+        }
+    );
+
+    let root_hash = match deleted {
+        Ok(root_hash) => root_hash,
+
+        Err(error::Error::Communication(Error::Http(StatusCode::NOT_FOUND))) => {
+            verify_absent(server, file)?;
+            return Ok(false);
+        }
+
+        Err(e) => return Err(e)
+    };
+
+    update_root_hash(server, root_hash)?;
+
+    confirm_change(server, verify_root_hash(server))?;
+
+    Ok(true)
+}
+
+
+/// Download several files from the server, verify all of them at once and decrypt them
+pub fn download_files(files: &[FileID], password: &[u8], server: &Server) -> Result<Vec<Vec<u8>>, error::Error> {
+    let mut downloads = Vec::with_capacity(files.len());
+
+    for &file in files {
+        downloads.push((file, download(server, &routes::file(file))?));
+    }
+
+    {
+        let contents: Vec<(FileID, &[u8])> = downloads.iter()
+            .map(|&(file, ref bytes)| (file, &bytes[..]))
+            .collect();
 
-    verify_file(client, file, &bytes)
-        .expect("Failed to verify authenticity of file");
+        verify_files(server, &contents)?;
+    }
 
-    let secret = Secret::from_bytes(&bytes)
-        .expect("Failed to interpret file");
+    let mut decrypted = Vec::with_capacity(downloads.len());
 
-    secret.reveal(password, &verification)
-        .expect("Failed to decrypt file")
+    for (file, bytes) in downloads {
+        let secret = Secret::from_bytes(&bytes)?;
+        decrypted.push(secret.reveal(password, &serialize_file_id(file))?);
+    }
+
+    Ok(decrypted)
+}
+
+
+/// Accept the changes made on the server by others since the client last saw
+/// it, once they have been verified and found to be signed by this device or a
+/// trusted one. Returns the changes.
+pub fn synchronize(server: &Server) -> Result<Vec<LeafChange>, error::Error> {
+    Ok(fast_forward_root_hash(server)?)
+}
+
+
+/// Ask the server what it supports. Fails unless this client is able to verify it.
+pub fn server_info(server: &Server) -> Result<Info, error::Error> {
+    Ok(check_server_info(server)?)
+}
+
+
+/// Returns the client's root hash and the server's, which differ if either
+/// side has changed since the client last synchronized
+pub fn root_hashes(server: &Server) -> Result<(Hash, Hash), error::Error> {
+    Ok((load_root_hash(server)?, fetch_root_hash(server)?))
 }
 
 
-/// Convert a file into a byte array
-fn serialize_file_id(id: FileID) -> [u8; 2] {
-    use std::mem::transmute;
-    unsafe { transmute(id.to_be()) }
+/// Returns the public key this device signs its changes with, in hexadecimal. Other
+/// devices accept its changes once the key is added to their `trusted_devices` file.
+pub fn device_key() -> Result<String, error::Error> {
+    Ok(device_public_key()?)
 }
 
 
-/// Upload some bytes to the server
-fn upload(client: &Client, uri: &str, data: Vec<u8>) -> reqwest::Result<StatusCode> {
-    client.put(uri)
+/// Download a file and verify it. Returns the encrypted file, or `None` if the server
+/// proves that the file does not exist.
+fn download_verified(file: FileID, server: &Server) -> Result<Option<File>, error::Error> {
+    let mut encrypted = tempfile::tempfile()?;
+
+    match download_to(server, &routes::file(file), &mut encrypted) {
+        Ok(()) => {},
+
+        Err(Error::Http(StatusCode::NOT_FOUND)) => {
+            verify_absent(server, file)?;
+            return Ok(None);
+        }
+
+        Err(e) => return Err(error::Error::Communication(e))
+    };
+
+    let hasher = leaf_hasher(server)?;
+    let leaf = hash_file(hasher, &mut encrypted)?;
+
+    verify_file(server, file, &leaf)?;
+
+    Ok(Some(encrypted))
+}
+
+
+/// Make a change to the server's tree, on the condition that the server still has the
+/// client's root hash. `compute` returns the root hash the server must have and the one
+/// the change leads to, and `send` makes the change. If another writer changes the tree
+/// first, the client catches up with the server and tries again with fresh proofs.
+/// Returns the new root hash, or the error `send` failed with.
+fn change_tree<C, S>(server: &Server, compute: C, send: S) -> Result<Hash, error::Error>
+    where C: Fn() -> verification::Result<(Hash, Hash)>,
+          S: Fn(&Hash) -> Result<(), error::Error>
+{
+    for _ in 0..MAX_ATTEMPTS {
+        let (expected, root_hash) = match compute() {
+            Ok(hashes) => hashes,
+
+            Err(verification::Error::HashOutOfDate { .. }) => {
+                catch_up(server)?;
+                continue;
+            }
+
+            Err(e) => return Err(error::Error::Verification(e))
+        };
+
+        match send(&expected) {
+            Ok(()) => return Ok(root_hash),
+            Err(error::Error::Communication(Error::Http(StatusCode::PRECONDITION_FAILED))) => catch_up(server)?,
+            Err(e) => return Err(e)
+        }
+    }
+
+    Err(error::Error::TooManyAttempts)
+}
+
+
+/// Check a change once the server has accepted it. If another writer has changed the
+/// tree since, the server instead has to prove that its tree was reached from the one
+/// the change led to.
+fn confirm_change(server: &Server, check: verification::Result<()>) -> Result<(), error::Error> {
+    match check {
+        Ok(()) => Ok(()),
+        Err(verification::Error::HashOutOfDate { .. }) => catch_up(server),
+        Err(e) => Err(error::Error::Verification(e))
+    }
+}
+
+
+/// Move the client's root hash forward to the server's
+fn catch_up(server: &Server) -> Result<(), error::Error> {
+    fast_forward_root_hash(server)?;
+    Ok(())
+}
+
+
+/// How new files are encrypted, with the cipher configured for the server
+fn encryption_options(server: &Server) -> Options {
+    Options {
+        cipher: server.cipher(),
+        ..Options::default()
+    }
+}
+
+
+/// Convert a file into a big-endian byte array
+fn serialize_file_id(id: FileID) -> Vec<u8> {
+    to_big_endian(id as u64, FILE_ID_BITS as usize / 8)
+}
+
+
+/// Hash a whole file into a leaf of the client's tree
+fn hash_file(mut hasher: LeafHasher, file: &mut File) -> io::Result<Hash> {
+    file.seek(SeekFrom::Start(0))?;
+    io::copy(file, &mut hasher)?;
+
+    Ok(hasher.finish())
+}
+
+
+/// Upload some bytes to the server, if its tree still has the expected root hash
+fn upload<T: Into<Body>>(server: &Server, route: &str, expected: &Hash, signature: &[u8], data: T) -> Result<(), Error> {
+    server.client().put(&server.uri(route))
+        .header(EXPECTED_ROOT_HEADER, protocol::encode_expected_root(expected))
+        .header(CHANGE_SIGNATURE_HEADER, protocol::encode_signature(signature))
         .body(data)
         .send()
-        .map(|response| response.status())
+        .map_err(|error| Error::Reqwest(error))
+        .and_then(|response| match response.status() {
+            StatusCode::OK | StatusCode::CREATED => Ok(()),
+            code => Err(Error::Http(code))
+        })
+}
+
+/// Remove something from the server, if its tree still has the expected root hash
+fn delete(server: &Server, route: &str, expected: &Hash, signature: &[u8]) -> Result<(), Error> {
+    server.client().delete(&server.uri(route))
+        .header(EXPECTED_ROOT_HEADER, protocol::encode_expected_root(expected))
+        .header(CHANGE_SIGNATURE_HEADER, protocol::encode_signature(signature))
+        .send()
+        .map_err(|error| Error::Reqwest(error))
+        .and_then(|response| match response.status() {
+            StatusCode::OK => Ok(()),
+            code => Err(Error::Http(code))
+        })
 }
 
 /// Download some bytes from the server
-fn download(client: &Client, uri: &str) -> Result<Vec<u8>, Error> {
-    client.get(uri)
+fn download(server: &Server, route: &str) -> Result<Vec<u8>, Error> {
+    server.client().get(&server.uri(route))
         .send()
         .map_err(|error| Error::Reqwest(error))
         .and_then(|response| match response.status() {
             StatusCode::OK => Ok(response),
             code => Err(Error::Http(code))
         })
-        .map(|response| response
-                .bytes()
-                .map(Result::unwrap)
-                .collect()
-        )
+        .and_then(read_body)
+}
+
+/// Download some bytes from the server, writing them to `writer` as they arrive
+fn download_to<W: Write>(server: &Server, route: &str, writer: &mut W) -> Result<(), Error> {
+    let mut response = server.client().get(&server.uri(route))
+        .send()
+        .map_err(|error| Error::Reqwest(error))?;
+
+    match response.status() {
+        StatusCode::OK => {},
+        code => return Err(Error::Http(code))
+    }
+
+    response.copy_to(writer)
+        .map(|_| ())
+        .map_err(|error| Error::Reqwest(error))
 }
 
+/// Download the bytes from `start` until, but not including, `end` of a file on the server
+fn download_range_bytes(server: &Server, route: &str, start: u64, end: u64) -> Result<Vec<u8>, Error> {
+    let response = server.client().get(&server.uri(route))
+        .header(RANGE, format!("bytes={}-{}", start, end - 1))
+        .send()
+        .map_err(|error| Error::Reqwest(error))?;
+
+    let status = response.status();
+
+    let bytes = match status {
+        StatusCode::PARTIAL_CONTENT | StatusCode::OK => read_body(response)?,
+        code => return Err(Error::Http(code))
+    };
+
+    // The server may ignore the range and send the whole file
+    if status == StatusCode::OK {
+        if (bytes.len() as u64) < end {
+            return Err(Error::InvalidResponse);
+        }
 
-/// Get the URI to a file on the server
-fn file_uri(file: FileID) -> String {
-    format!("http://localhost:8000/file/{id}", id = file)
+        return Ok(bytes[start as usize..end as usize].to_vec());
+    }
+
+    Ok(bytes)
 }
 
+/// Send some bytes to the server and download the response
+fn exchange(server: &Server, route: &str, data: Vec<u8>) -> Result<Vec<u8>, Error> {
+    server.client().post(&server.uri(route))
+        .body(data)
+        .send()
+        .map_err(|error| Error::Reqwest(error))
+        .and_then(|response| match response.status() {
+            StatusCode::OK => Ok(response),
+            code => Err(Error::Http(code))
+        })
+        .and_then(read_body)
+}
+
+
+/// Read the whole body of a response
+fn read_body(mut response: Response) -> Result<Vec<u8>, Error> {
+    let mut bytes = Vec::new();
+
+    response.copy_to(&mut bytes)
+        .map_err(|error| Error::Reqwest(error))?;
+
+    Ok(bytes)
+}
@@ -1,10 +1,7 @@
 use ring::{
-    self,
-    error::Unspecified,
     aead::{
         SealingKey,
         OpeningKey,
-        AES_128_GCM,
         seal_in_place,
         open_in_place,
     },
@@ -12,18 +9,96 @@ use ring::{
         SystemRandom,
         SecureRandom,
     },
-    pbkdf2,
-    digest,
 };
 
-static ENCRYPTION_ALGORITHM: &'static ring::aead::Algorithm = &AES_128_GCM;
-static KEY_DIGEST_ALGORITM: &'static digest::Algorithm = &digest::SHA256;
+use chacha20poly1305::{
+    Key,
+    XChaCha20Poly1305,
+    XNonce,
+    aead::{
+        Aead,
+        NewAead,
+        Payload,
+    },
+};
+
+use std::{
+    self,
+    fmt,
+    io::{
+        self,
+        Read,
+        Write,
+    },
+};
+
+use protocol::to_big_endian;
+
+use super::envelope::{
+    self,
+    Header,
+    Cipher,
+    Kdf,
+};
+
+/// The iterations of PBKDF2 used by files stored without an envelope
 static PBKDF2_ITERATIONS: u32 = 47_131;
 
-/// Stores encrypted data
+static SALT_LEN: usize = 16;
+
+/// The number of bytes encrypted in every chunk but the last
+static CHUNK_SIZE: u32 = 64 * 1024;
+
+/// The version of the format with a salt, but no envelope
+static SALTED_VERSION: u8 = 1;
+
+/// The salt used by secrets stored before every secret got its own
+static LEGACY_SALT: [u8; 8] = [0, 1, 2, 3, 4, 5, 6, 7];
+
+/// How new secrets are encrypted
+#[derive(Clone, Copy, Debug)]
+pub struct Options {
+    pub cipher: Cipher,
+    pub kdf: Kdf,
+}
+
+/// Stores encrypted data.
+///
+/// Stored as an envelope: a header describing how the data was encrypted (see
+/// the `envelope` module), followed by the encrypted data. Older secrets are
+/// stored in one of two formats without an envelope, which are read as well:
+///
+/// - Version 1: the version byte, the salt, the nonce and the encrypted data
+/// - Version 0: the encrypted data followed by the nonce, with a fixed salt
+///
+/// New secrets are encrypted in chunks using the STREAM construction: every
+/// chunk gets a nonce made from the nonce prefix in the header, the index of
+/// the chunk and a flag set only on the last chunk. The index and flag are
+/// also authenticated with the chunk, so chunks can't be reordered, dropped
+/// or added to the end without decryption failing.
 pub struct Secret {
+    header: Header,
     data: Vec<u8>,
-    nonce: Vec<u8>,
+
+    /// Old secrets have no magic number, so one that happens to start with the
+    /// version byte of the salted format is also kept in the oldest format
+    legacy: Option<Box<Secret>>,
+}
+
+/// Decrypts parts of data encrypted in chunks, using only the chunks holding them
+pub struct RangeDecryptor {
+    header: Header,
+    key: Vec<u8>,
+    additional_data: Vec<u8>,
+
+    /// The length of the header, in bytes
+    header_len: u64,
+
+    /// The number of encrypted chunks
+    chunk_count: u64,
+
+    /// The length of the decrypted data, in bytes
+    length: u64,
 }
 
 #[derive(Debug)]
@@ -33,83 +108,497 @@ pub enum Error {
 
     /// The supplied data was too short
     InvalidLength,
+
+    /// The header of the envelope is malformed
+    InvalidHeader,
+
+    /// The envelope has a version this client does not understand
+    UnknownVersion(u8),
+
+    /// The data was encrypted with a cipher this client does not support
+    UnknownCipher(u8),
+
+    /// The key was derived with a function this client does not support
+    UnknownKdf(u8),
+
+    /// Could not read the data to encrypt or decrypt, or write the result
+    Io(io::Error),
+
+    /// The data is encrypted as a single message, so it can only be decrypted as a whole
+    NotChunked,
+}
+
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::AuthenticationFailed =>
+                write!(f, "the file could not be decrypted: the password is wrong, or the file was tampered with"),
+            Error::InvalidLength => write!(f, "the encrypted file is too short"),
+            Error::InvalidHeader => write!(f, "the header of the encrypted file is malformed"),
+            Error::UnknownVersion(version) => write!(f, "the file is encrypted in version {}, which this client does not know", version),
+            Error::UnknownCipher(cipher) => write!(f, "the file is encrypted with cipher {}, which this client does not support", cipher),
+            Error::UnknownKdf(kdf) => write!(f, "the file's key is derived with function {}, which this client does not support", kdf),
+            Error::Io(ref e) => write!(f, "could not read or write the file: {}", e),
+            Error::NotChunked => write!(f, "the file is encrypted as a single message, so parts of it can't be decrypted"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(std::error::Error + 'static)> {
+        match *self {
+            Error::Io(ref e) => Some(e),
+            _ => None
+        }
+    }
+}
+
+
+impl Default for Options {
+    /// XChaCha20-Poly1305 with a key derived by Argon2id
+    fn default() -> Options {
+        Options {
+            cipher: Cipher::XChaCha20Poly1305,
+            kdf: Kdf::DEFAULT_ARGON2ID,
+        }
+    }
 }
 
 
 impl Secret {
+    /// Encrypt some data using the default options
     pub fn new(password: &[u8], data: &[u8], verification: &[u8]) -> Secret {
-        let key = generate_key(password);
-        let nonce = generate_nonce();
+        Secret::with_options(password, data, verification, Options::default())
+    }
 
-        let data = encrypt(&key, &nonce, data, verification).unwrap();
+    /// Encrypt some data with a specific cipher and key derivation function
+    pub fn with_options(password: &[u8], data: &[u8], verification: &[u8], options: Options) -> Secret {
+        let mut encrypted = Vec::new();
 
-        Secret {
-            data,
-            nonce,
-        }
+        encrypt_stream(password, verification, options, &mut &data[..], &mut encrypted).unwrap();
+
+        Secret::from_bytes(&encrypted).unwrap()
     }
 
 
     /// Create a new secret from some bytes
     pub fn from_bytes(bytes: &[u8]) -> Result<Secret, Error> {
-        if bytes.len() < ENCRYPTION_ALGORITHM.nonce_len() {
+        if bytes.starts_with(envelope::MAGIC) {
+            let mut data = bytes;
+            let header = Header::read(&mut data)?;
+
+            return Ok(Secret {
+                header,
+                data: data.to_vec(),
+                legacy: None,
+            });
+        }
+
+        let legacy = Secret::from_legacy_bytes(bytes);
+
+        let nonce_len = Cipher::Aes128Gcm.nonce_len();
+
+        if bytes.len() >= 1 + SALT_LEN + nonce_len && bytes[0] == SALTED_VERSION {
+            let (salt, rest) = bytes[1..].split_at(SALT_LEN);
+            let (nonce, data) = rest.split_at(nonce_len);
+
+            Ok(Secret {
+                header: Header {
+                    version: SALTED_VERSION,
+                    cipher: Cipher::Aes128Gcm,
+                    kdf: Kdf::Pbkdf2 {
+                        iterations: PBKDF2_ITERATIONS
+                    },
+                    salt: salt.to_vec(),
+                    nonce: nonce.to_vec(),
+                    chunk_size: None,
+                },
+                data: data.to_vec(),
+                legacy: legacy.ok().map(Box::new),
+            })
+        } else {
+            legacy
+        }
+    }
+
+    /// Create a secret from bytes stored before the format had a version
+    fn from_legacy_bytes(bytes: &[u8]) -> Result<Secret, Error> {
+        let nonce_len = Cipher::Aes128Gcm.nonce_len();
+
+        if bytes.len() < nonce_len {
             Err(Error::InvalidLength)
         } else {
-            let (data, nonce) = bytes.split_at(bytes.len() - ENCRYPTION_ALGORITHM.nonce_len());
+            let (data, nonce) = bytes.split_at(bytes.len() - nonce_len);
 
             Ok(Secret {
+                header: Header {
+                    version: 0,
+                    cipher: Cipher::Aes128Gcm,
+                    kdf: Kdf::Pbkdf2 {
+                        iterations: PBKDF2_ITERATIONS
+                    },
+                    salt: LEGACY_SALT.to_vec(),
+                    nonce: nonce.to_vec(),
+                    chunk_size: None,
+                },
                 data: data.to_vec(),
-                nonce: nonce.to_vec(),
+                legacy: None,
             })
         }
     }
 
     /// Get the secret as bytes
     pub fn as_bytes(&self) -> Vec<u8> {
-        let mut data = self.data.clone();
-        data.extend_from_slice(&self.nonce);
+        let mut data = self.header.to_bytes();
+        data.extend_from_slice(&self.data);
         data
     }
 
 
     /// Reveals the secret, consuming it in the process
     pub fn reveal(self, password: &[u8], verification: &[u8]) -> Result<Vec<u8>, Error> {
-        let key = generate_key(password);
+        let key = generate_key(&self.header, password)?;
+        let additional_data = self.header.additional_data(verification);
 
-        match decrypt(&key, &self.nonce, &self.data, verification) {
-            Ok(data) => {
-                Ok(data)
-            }
-            Err(_) => {
-                Err(Error::AuthenticationFailed)
+        let revealed = match self.header.chunk_size {
+            Some(_) => {
+                let mut data = Vec::new();
+
+                decrypt_chunks(&self.header, &key, &additional_data, &mut &self.data[..], &mut data)
+                    .map(|_| data)
             }
+
+            None => decrypt(self.header.cipher, &key, &self.header.nonce, &self.data, &additional_data)
+                .map_err(|_| Error::AuthenticationFailed)
+        };
+
+        match revealed {
+            Err(Error::AuthenticationFailed) => match self.legacy {
+                Some(legacy) => legacy.reveal(password, verification),
+                None => Err(Error::AuthenticationFailed)
+            },
+
+            revealed => revealed
+        }
+    }
+}
+
+
+impl RangeDecryptor {
+    /// Prepare to decrypt parts of some encrypted data, given the start of it, which
+    /// holds the whole header, and the length of all of it
+    pub fn new(password: &[u8], verification: &[u8], start: &[u8], length: u64) -> Result<RangeDecryptor, Error> {
+        if !start.starts_with(envelope::MAGIC) {
+            return Err(Error::NotChunked);
+        }
+
+        let mut data = start;
+        let header = Header::read(&mut data)?;
+
+        let chunk_size = match header.chunk_size {
+            Some(chunk_size) => chunk_size as u64,
+            None => return Err(Error::NotChunked)
+        };
+
+        let header_len = (start.len() - data.len()) as u64;
+        let tag_len = header.cipher.tag_len() as u64;
+        let encrypted_chunk = chunk_size + tag_len;
+
+        let encrypted = length.checked_sub(header_len).ok_or(Error::InvalidLength)?;
+        let partial = if encrypted % encrypted_chunk == 0 { 0 } else { 1 };
+        let chunk_count = encrypted / encrypted_chunk + partial;
+
+        // Every chunk, even an empty last one, ends with a whole tag
+        if chunk_count == 0 || chunk_count - 1 > u32::max_value() as u64
+            || encrypted - (chunk_count - 1) * encrypted_chunk < tag_len {
+            return Err(Error::InvalidLength);
+        }
+
+        Ok(RangeDecryptor {
+            key: generate_key(&header, password)?,
+            additional_data: header.additional_data(verification),
+            header,
+            header_len,
+            chunk_count,
+            length: encrypted - chunk_count * tag_len,
+        })
+    }
+
+
+    /// The length of all the decrypted data
+    pub fn len(&self) -> u64 {
+        self.length
+    }
+
+
+    /// The part of the encrypted data, as its first byte and the byte after its last,
+    /// holding a range of the decrypted data. The range must not be empty, and must
+    /// be within the data.
+    pub fn encrypted_range(&self, offset: u64, length: u64) -> (u64, u64) {
+        let (first, last) = self.chunks(offset, length);
+        let encrypted_chunk = self.encrypted_chunk_len() as u64;
+
+        let start = self.header_len + first * encrypted_chunk;
+        let end = self.header_len + (last + 1) * encrypted_chunk;
+        let data_end = self.header_len + self.length + self.chunk_count * self.header.cipher.tag_len() as u64;
+
+        (start, end.min(data_end))
+    }
+
+
+    /// Decrypt a range of the data, given the part of the encrypted data returned by
+    /// `encrypted_range` for it
+    pub fn decrypt(&self, offset: u64, length: u64, encrypted: &[u8]) -> Result<Vec<u8>, Error> {
+        if length == 0 || offset >= self.length || length > self.length - offset {
+            return Err(Error::InvalidLength);
+        }
+
+        let (first, last) = self.chunks(offset, length);
+
+        let chunks: Vec<&[u8]> = encrypted.chunks(self.encrypted_chunk_len()).collect();
+        if chunks.len() as u64 != last - first + 1 {
+            return Err(Error::InvalidLength);
+        }
+
+        let mut data = Vec::new();
+
+        for (index, chunk) in (first..last + 1).zip(chunks) {
+            let is_last = index == self.chunk_count - 1;
+
+            let decrypted = decrypt(
+                self.header.cipher,
+                &self.key,
+                &chunk_nonce(&self.header, index as u32, is_last),
+                chunk,
+                &chunk_additional_data(&self.additional_data, index as u32, is_last)
+            ).map_err(|_| Error::AuthenticationFailed)?;
+
+            data.extend_from_slice(&decrypted);
+        }
+
+        let start = (offset - first * self.chunk_size()) as usize;
+        let end = start + length as usize;
+
+        if end > data.len() {
+            return Err(Error::InvalidLength);
+        }
+
+        Ok(data[start..end].to_vec())
+    }
+
+
+    /// The first and last chunk holding a range of the decrypted data
+    fn chunks(&self, offset: u64, length: u64) -> (u64, u64) {
+        let first = offset / self.chunk_size();
+        let last = (offset + length.max(1) - 1) / self.chunk_size();
+
+        (first, last)
+    }
+
+    fn chunk_size(&self) -> u64 {
+        self.header.chunk_size.unwrap() as u64
+    }
+
+    fn encrypted_chunk_len(&self) -> usize {
+        self.chunk_size() as usize + self.header.cipher.tag_len()
+    }
+}
+
+
+/// Encrypt everything read from `reader` a chunk at a time, and write it to
+/// `writer` as an envelope. Only a couple of chunks are held in memory at once.
+pub fn encrypt_stream<R: Read, W: Write>(password: &[u8], verification: &[u8], options: Options,
+                                         reader: &mut R, writer: &mut W) -> Result<(), Error> {
+    let header = Header {
+        version: envelope::VERSION,
+        cipher: options.cipher,
+        kdf: options.kdf,
+        salt: generate_bytes(SALT_LEN),
+        nonce: generate_bytes(options.cipher.nonce_len() - envelope::NONCE_SUFFIX_LEN),
+        chunk_size: Some(CHUNK_SIZE),
+    };
+
+    let key = generate_key(&header, password)?;
+    let additional_data = header.additional_data(verification);
+
+    writer.write_all(&header.to_bytes()).map_err(Error::Io)?;
+
+    let chunk_size = CHUNK_SIZE as usize;
+    let mut chunk = read_chunk(reader, chunk_size)?;
+    let mut index = 0;
+
+    loop {
+        // A full chunk may be followed by more data, or be the last one
+        let next = if chunk.len() == chunk_size {
+            read_chunk(reader, chunk_size)?
+        } else {
+            Vec::new()
+        };
+
+        let last = next.is_empty();
+
+        let encrypted = encrypt(
+            header.cipher,
+            &key,
+            &chunk_nonce(&header, index, last),
+            &chunk,
+            &chunk_additional_data(&additional_data, index, last)
+        ).unwrap();
+
+        writer.write_all(&encrypted).map_err(Error::Io)?;
+
+        if last {
+            return Ok(());
+        }
+
+        chunk = next;
+        index = index.checked_add(1).ok_or(Error::InvalidLength)?;
+    }
+}
+
+
+/// Decrypt an envelope read from `reader`, and write the data to `writer`.
+///
+/// Files encrypted in chunks are decrypted a chunk at a time, and each chunk is
+/// written as soon as it has been authenticated. If an error is returned, what
+/// was written has to be discarded, since the file may have been truncated.
+/// Files stored in older formats are read into memory in full.
+pub fn decrypt_stream<R: Read, W: Write>(password: &[u8], verification: &[u8],
+                                         reader: &mut R, writer: &mut W) -> Result<(), Error> {
+    let magic = read_chunk(reader, envelope::MAGIC.len())?;
+
+    if &magic[..] == &envelope::MAGIC[..] {
+        let header = Header::read(&mut (&magic[..]).chain(&mut *reader))?;
+
+        if header.chunk_size.is_some() {
+            let key = generate_key(&header, password)?;
+            let additional_data = header.additional_data(verification);
+
+            return decrypt_chunks(&header, &key, &additional_data, reader, writer);
         }
+
+        // Put the header back in front of the message
+        let mut bytes = header.to_bytes();
+        reader.read_to_end(&mut bytes).map_err(Error::Io)?;
+
+        let data = Secret::from_bytes(&bytes)?.reveal(password, verification)?;
+        return writer.write_all(&data).map_err(Error::Io);
     }
+
+    // Older formats are a single message, which has to be read in full
+    let mut bytes = magic;
+    reader.read_to_end(&mut bytes).map_err(Error::Io)?;
+
+    let data = Secret::from_bytes(&bytes)?.reveal(password, verification)?;
+    writer.write_all(&data).map_err(Error::Io)
 }
 
 
-/// Generate a private key from a password
-fn generate_key(password: &[u8]) -> Vec<u8> {
-    let salt = [0, 1, 2, 3, 4, 5, 6, 7];
-    let mut hashed_password = vec![0; ENCRYPTION_ALGORITHM.key_len()];
+/// Decrypt and authenticate every chunk read from `reader`, writing the data to `writer`
+fn decrypt_chunks<R: Read, W: Write>(header: &Header, key: &[u8], additional_data: &[u8],
+                                     reader: &mut R, writer: &mut W) -> Result<(), Error> {
+    let chunk_size = header.chunk_size.unwrap() as usize + header.cipher.tag_len();
 
-    pbkdf2::derive(KEY_DIGEST_ALGORITM, PBKDF2_ITERATIONS, &salt, password, &mut hashed_password);
+    let mut chunk = read_chunk(reader, chunk_size)?;
+    let mut index = 0;
 
-    hashed_password
+    loop {
+        let next = if chunk.len() == chunk_size {
+            read_chunk(reader, chunk_size)?
+        } else {
+            Vec::new()
+        };
+
+        // If the file was truncated at the end of a chunk, that chunk was not
+        // encrypted as the last one and fails to decrypt
+        let last = next.is_empty();
+
+        let data = decrypt(
+            header.cipher,
+            key,
+            &chunk_nonce(header, index, last),
+            &chunk,
+            &chunk_additional_data(additional_data, index, last)
+        ).map_err(|_| Error::AuthenticationFailed)?;
+
+        writer.write_all(&data).map_err(Error::Io)?;
+
+        if last {
+            return Ok(());
+        }
+
+        chunk = next;
+        index = index.checked_add(1).ok_or(Error::InvalidLength)?;
+    }
 }
 
-/// Generate a nonce for encryption/decryption
-fn generate_nonce() -> Vec<u8> {
-    let mut nonce = vec![0; ENCRYPTION_ALGORITHM.nonce_len()];
-    SystemRandom::new().fill(&mut nonce).unwrap();
+
+/// Read up to `size` bytes, fewer only if the reader runs out of data
+fn read_chunk<R: Read>(reader: &mut R, size: usize) -> Result<Vec<u8>, Error> {
+    let mut chunk = Vec::with_capacity(size);
+
+    reader.take(size as u64)
+        .read_to_end(&mut chunk)
+        .map_err(Error::Io)?;
+
+    Ok(chunk)
+}
+
+/// The nonce of a chunk: the prefix in the header, followed by the index of
+/// the chunk and a flag set only on the last chunk
+fn chunk_nonce(header: &Header, index: u32, last: bool) -> Vec<u8> {
+    let mut nonce = header.nonce.clone();
+    nonce.extend(to_big_endian(index as u64, 4));
+    nonce.push(last as u8);
     nonce
 }
 
+/// The data authenticated along with a chunk
+fn chunk_additional_data(additional_data: &[u8], index: u32, last: bool) -> Vec<u8> {
+    let mut data = additional_data.to_vec();
+    data.extend(to_big_endian(index as u64, 4));
+    data.push(last as u8);
+    data
+}
+
+
+/// Generate a private key from a password, as described by a header
+fn generate_key(header: &Header, password: &[u8]) -> Result<Vec<u8>, Error> {
+    let mut hashed_password = vec![0; header.cipher.key_len()];
+
+    header.kdf.derive(password, &header.salt, &mut hashed_password)?;
+
+    Ok(hashed_password)
+}
+
+/// Generate random bytes for a salt or a nonce
+fn generate_bytes(count: usize) -> Vec<u8> {
+    let mut bytes = vec![0; count];
+    SystemRandom::new().fill(&mut bytes).unwrap();
+    bytes
+}
+
 /// Encrypt and sign some data using a private key, a nonce, and an unique identifier for
 /// verification.
-fn encrypt(key: &[u8], nonce: &[u8], data: &[u8], verification_data: &[u8]) -> Result<Vec<u8>, Unspecified> {
+fn encrypt(cipher: Cipher, key: &[u8], nonce: &[u8], data: &[u8], verification_data: &[u8]) -> Result<Vec<u8>, ()> {
+    let algorithm = match cipher.ring_algorithm() {
+        Some(algorithm) => algorithm,
+
+        None => {
+            let payload = Payload {
+                msg: data,
+                aad: verification_data,
+            };
+
+            return XChaCha20Poly1305::new(Key::from_slice(key))
+                .encrypt(XNonce::from_slice(nonce), payload)
+                .map_err(|_| ());
+        }
+    };
+
     let key = SealingKey::new(
-        ENCRYPTION_ALGORITHM,
+        algorithm,
         &key,
     ).unwrap();
 
@@ -120,15 +609,31 @@ fn encrypt(key: &[u8], nonce: &[u8], data: &[u8], verification_data: &[u8]) -> R
     }
 
     // Encrypt and sign
-    seal_in_place(&key, nonce, verification_data, &mut buffer, ENCRYPTION_ALGORITHM.tag_len())
+    seal_in_place(&key, nonce, verification_data, &mut buffer, algorithm.tag_len())
         .map(|_| buffer)
+        .map_err(|_| ())
 }
 
 /// Verify and decrypt some data using a private key, a nonce, and an unique identifier for
 /// verifying the authenticity of the data.
-fn decrypt(key: &[u8], nonce: &[u8], ciphertext: &[u8], verification_data: &[u8]) -> Result<Vec<u8>, Unspecified> {
+fn decrypt(cipher: Cipher, key: &[u8], nonce: &[u8], ciphertext: &[u8], verification_data: &[u8]) -> Result<Vec<u8>, ()> {
+    let algorithm = match cipher.ring_algorithm() {
+        Some(algorithm) => algorithm,
+
+        None => {
+            let payload = Payload {
+                msg: ciphertext,
+                aad: verification_data,
+            };
+
+            return XChaCha20Poly1305::new(Key::from_slice(key))
+                .decrypt(XNonce::from_slice(nonce), payload)
+                .map_err(|_| ());
+        }
+    };
+
     let key = OpeningKey::new(
-        ENCRYPTION_ALGORITHM,
+        algorithm,
         &key,
     ).unwrap();
 
@@ -137,4 +642,208 @@ fn decrypt(key: &[u8], nonce: &[u8], ciphertext: &[u8], verification_data: &[u8]
     // Authenticate and decrypt
     open_in_place(&key, nonce, verification_data, 0, &mut buffer)
         .map(|a| a.to_vec())
+        .map_err(|_| ())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    static PASSWORD: &[u8] = b"password";
+    static VERIFICATION: &[u8] = b"file";
+
+    /// Cheap to derive a key with, so the tests stay fast
+    fn options() -> Options {
+        Options {
+            cipher: Cipher::XChaCha20Poly1305,
+            kdf: Kdf::Pbkdf2 { iterations: 1 },
+        }
+    }
+
+    /// Some data in which every chunk differs from the others
+    fn data(length: usize) -> Vec<u8> {
+        (0..length).map(|i| (i / CHUNK_SIZE as usize + i) as u8).collect()
+    }
+
+    fn encrypted(data: &[u8]) -> Vec<u8> {
+        let mut encrypted = Vec::new();
+        encrypt_stream(PASSWORD, VERIFICATION, options(), &mut &data[..], &mut encrypted).unwrap();
+        encrypted
+    }
+
+    fn decrypted(encrypted: &[u8]) -> Result<Vec<u8>, Error> {
+        let mut data = Vec::new();
+        decrypt_stream(PASSWORD, VERIFICATION, &mut &encrypted[..], &mut data).map(|_| data)
+    }
+
+    /// The length of the header and of every encrypted chunk
+    fn layout(encrypted: &[u8]) -> (usize, usize) {
+        let header = Header::read(&mut &encrypted[..]).unwrap();
+        (header.to_bytes().len(), CHUNK_SIZE as usize + header.cipher.tag_len())
+    }
+
+    fn authentication_failed(result: Result<Vec<u8>, Error>) -> bool {
+        match result {
+            Err(Error::AuthenticationFailed) => true,
+            _ => false
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let chunk = CHUNK_SIZE as usize;
+
+        for &length in &[0, chunk - 1, chunk, chunk + 1, 3 * chunk] {
+            let data = data(length);
+            let encrypted = encrypted(&data);
+
+            assert_eq!(data, decrypted(&encrypted).unwrap());
+            assert_eq!(data, Secret::from_bytes(&encrypted).unwrap().reveal(PASSWORD, VERIFICATION).unwrap());
+        }
+    }
+
+    #[test]
+    fn refuse_wrong_password() {
+        let encrypted = encrypted(&data(10));
+        let mut data = Vec::new();
+
+        assert!(authentication_failed(
+            decrypt_stream(b"wrong", VERIFICATION, &mut &encrypted[..], &mut data).map(|_| data)
+        ));
+    }
+
+    #[test]
+    fn refuse_truncated_files() {
+        let encrypted = encrypted(&data(3 * CHUNK_SIZE as usize));
+        let (header_len, chunk_len) = layout(&encrypted);
+
+        for chunks in 1..3 {
+            let truncated = &encrypted[..header_len + chunks * chunk_len];
+
+            assert!(authentication_failed(decrypted(truncated)));
+            assert!(authentication_failed(Secret::from_bytes(truncated).unwrap().reveal(PASSWORD, VERIFICATION)));
+        }
+    }
+
+    #[test]
+    fn refuse_reordered_chunks() {
+        let mut encrypted = encrypted(&data(3 * CHUNK_SIZE as usize));
+        let (header_len, chunk_len) = layout(&encrypted);
+
+        let first = encrypted[header_len..header_len + chunk_len].to_vec();
+        let second = encrypted[header_len + chunk_len..header_len + 2 * chunk_len].to_vec();
+
+        encrypted[header_len..header_len + chunk_len].copy_from_slice(&second);
+        encrypted[header_len + chunk_len..header_len + 2 * chunk_len].copy_from_slice(&first);
+
+        assert!(authentication_failed(decrypted(&encrypted)));
+    }
+
+    #[test]
+    fn refuse_extended_files() {
+        let mut encrypted = encrypted(&data(2 * CHUNK_SIZE as usize));
+        let (header_len, chunk_len) = layout(&encrypted);
+
+        let first = encrypted[header_len..header_len + chunk_len].to_vec();
+        encrypted.extend(first);
+
+        assert!(authentication_failed(decrypted(&encrypted)));
+    }
+
+    /// Decrypt a range using only the part of the encrypted data holding it
+    fn decrypted_range(encrypted: &[u8], offset: u64, length: u64) -> Result<Vec<u8>, Error> {
+        let decryptor = RangeDecryptor::new(PASSWORD, VERIFICATION, encrypted, encrypted.len() as u64)?;
+        let (start, end) = decryptor.encrypted_range(offset, length);
+
+        decryptor.decrypt(offset, length, &encrypted[start as usize..end as usize])
+    }
+
+    #[test]
+    fn decrypt_ranges() {
+        let chunk = CHUNK_SIZE as usize;
+        let data = data(2 * chunk + 100);
+        let encrypted = encrypted(&data);
+
+        let decryptor = RangeDecryptor::new(PASSWORD, VERIFICATION, &encrypted, encrypted.len() as u64).unwrap();
+        assert_eq!(data.len() as u64, decryptor.len());
+
+        let ranges = vec![
+            // Within the first chunk
+            (0, 10),
+            // Straddling a chunk boundary
+            (chunk - 10, 20),
+            // The whole of a chunk
+            (chunk, chunk),
+            // Ending in the final, partial chunk
+            (chunk + 50, chunk + 50),
+            (2 * chunk + 50, 50),
+            // The last byte
+            (2 * chunk + 99, 1),
+            // Everything
+            (0, data.len()),
+        ];
+
+        for (offset, length) in ranges {
+            assert_eq!(
+                &data[offset..offset + length],
+                &decrypted_range(&encrypted, offset as u64, length as u64).unwrap()[..]
+            );
+        }
+    }
+
+    #[test]
+    fn refuse_ranges_outside_the_data() {
+        let encrypted = encrypted(&data(100));
+        let decryptor = RangeDecryptor::new(PASSWORD, VERIFICATION, &encrypted, encrypted.len() as u64).unwrap();
+        let (start, end) = decryptor.encrypted_range(0, 100);
+        let chunk = &encrypted[start as usize..end as usize];
+
+        for &(offset, length) in &[(0, 0), (100, 1), (50, 51)] {
+            match decryptor.decrypt(offset, length, chunk) {
+                Err(Error::InvalidLength) => {}
+                _ => panic!("decrypted a range outside the data")
+            }
+        }
+    }
+
+    #[test]
+    fn refuse_tampered_ranges() {
+        let chunk = CHUNK_SIZE as u64;
+        let mut encrypted = encrypted(&data(2 * chunk as usize + 100));
+        let (header_len, chunk_len) = layout(&encrypted);
+
+        encrypted[header_len + chunk_len] ^= 1;
+
+        assert!(decrypted_range(&encrypted, 0, 10).is_ok());
+        assert!(authentication_failed(decrypted_range(&encrypted, chunk - 10, 20)));
+        assert!(authentication_failed(decrypted_range(&encrypted, chunk, 10)));
+    }
+
+    #[test]
+    fn decrypt_single_messages() {
+        let cipher = options().cipher;
+        let header = Header {
+            version: envelope::SINGLE_VERSION,
+            cipher,
+            kdf: options().kdf,
+            salt: generate_bytes(SALT_LEN),
+            nonce: generate_bytes(cipher.nonce_len()),
+            chunk_size: None,
+        };
+
+        let data = data(1000);
+        let key = generate_key(&header, PASSWORD).unwrap();
+
+        let mut encrypted = header.to_bytes();
+        encrypted.extend(encrypt(cipher, &key, &header.nonce, &data, &header.additional_data(VERIFICATION)).unwrap());
+
+        assert_eq!(data, decrypted(&encrypted).unwrap());
+        assert_eq!(data, Secret::from_bytes(&encrypted).unwrap().reveal(PASSWORD, VERIFICATION).unwrap());
+
+        match RangeDecryptor::new(PASSWORD, VERIFICATION, &encrypted, encrypted.len() as u64) {
+            Err(Error::NotChunked) => {}
+            _ => panic!("a single message was decrypted in parts")
+        }
+    }
 }
@@ -1,28 +1,49 @@
 
-use reqwest::{
-    Client,
-};
+use reqwest::StatusCode;
 
 use std::{
     self,
+    collections::BTreeMap,
+    fmt,
     fs::File,
     io::{
         self,
         Read,
         Write
     },
-    mem
 };
 
 use file_hash::{
+    LeafChange,
+    LeafHasher,
+};
+
+use protocol::{
+    self,
+    ChunkProof,
+    ConsistencyProof,
+    FileID,
+    FormatError,
     Hash,
-    hash,
-    MerkleTree
+    Incompatibility,
+    Info,
+    MerkleProof,
+    MultiProof,
+    ProofError,
+    TreeFormat,
+    FILE_ID_BITS,
+    routes,
 };
 
 use super::{
-    FileID,
+    Server,
     download,
+    exchange,
+};
+
+use super::device::{
+    self,
+    Devices,
 };
 
 
@@ -40,11 +61,20 @@ pub enum Error {
     /// The server's root hash could not be downloaded
     ServerHashNotFound(super::Error),
 
+    /// The format of the server's tree could not be downloaded
+    ServerFormatNotFound(super::Error),
+
+    /// The server uses a tree format this client does not support
+    UnknownTreeFormat(u8),
+
     /// The server's hash dependencies could not be downloaded
     ServerHashDependenciesNotFound(super::Error),
 
-    /// Got an unexpected amount of hash dependencies from the server
-    InvalidHashDependencyCount(usize),
+    /// Got a malformed proof from the server
+    InvalidProof(ProofError),
+
+    /// Got a proof for another file, or a tree of another depth or format, from the server
+    ProofMismatch,
 
     /// The server's and client's root hash did not match
     HashOutOfDate {
@@ -54,63 +84,417 @@ pub enum Error {
 
     /// The file has been modified by a third party
     TamperedFiles,
+
+    /// The server can't show how its tree was reached from the client's
+    InconsistentHistory,
+
+    /// The tree format can't tell an empty slot from a file, so absence can't be proven
+    InsecureTreeFormat(TreeFormat),
+
+    /// The tree format does not split files into chunks, so parts of files can't be verified
+    ChunksUnsupported(TreeFormat),
+
+    /// This device's key or the list of trusted devices could not be loaded
+    DeviceKeyNotFound(device::Error),
+
+    /// A change on the server was not signed by this device or one it trusts
+    UntrustedChange {
+        file: u64,
+        before: Hash
+    },
+
+    /// What the server supports could not be downloaded
+    ServerInfoNotFound(super::Error),
+
+    /// The server can't be verified by this client
+    IncompatibleServer(Incompatibility),
+}
+
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::ClientHashNotFound(ref e) => write!(f, "could not load the client's root hash: {}", e),
+            Error::ClientHashInvalid(ref e) => write!(f, "the client's root hash is invalid: {}", e),
+            Error::ClientHashNoWrite(ref e) => write!(f, "could not save the client's root hash: {}", e),
+            Error::ServerHashNotFound(ref e) => write!(f, "could not download the server's root hash: {}", e),
+            Error::ServerFormatNotFound(ref e) => write!(f, "could not download the format of the server's tree: {}", e),
+            Error::UnknownTreeFormat(format) => write!(f, "the server's tree is in format {}, which this client does not know", format),
+            Error::ServerHashDependenciesNotFound(ref e) => write!(f, "could not download the server's hash dependencies: {}", e),
+            Error::InvalidProof(ref e) => write!(f, "the server sent a malformed proof ({:?})", e),
+            Error::ProofMismatch => write!(f, "the server sent a proof of another file or tree"),
+            Error::HashOutOfDate { ref client, ref server } =>
+                write!(f, "the server's root hash {} does not match the client's {}", server.to_hex(), client.to_hex()),
+            Error::TamperedFiles => write!(f, "the files were modified by someone other than this client"),
+            Error::InconsistentHistory => write!(f, "the server can't show how its tree was reached from the client's"),
+            Error::InsecureTreeFormat(format) =>
+                write!(f, "the server's tree is in format {}, which can't prove that a file does not exist", format.version()),
+            Error::ChunksUnsupported(format) =>
+                write!(f, "the server's tree is in format {}, which can't verify parts of files", format.version()),
+            Error::DeviceKeyNotFound(ref e) => write!(f, "{}", e),
+            Error::UntrustedChange { file, ref before } =>
+                write!(f, "file {} was changed from the tree {} by a device this client does not trust", file, before.to_hex()),
+            Error::ServerInfoNotFound(ref e) => write!(f, "could not download what the server supports: {}", e),
+            Error::IncompatibleServer(ref incompatibility) =>
+                write!(f, "refusing to use a server this client can't verify: {}", incompatibility),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(std::error::Error + 'static)> {
+        match *self {
+            Error::ClientHashNotFound(ref e) |
+            Error::ClientHashInvalid(ref e) |
+            Error::ClientHashNoWrite(ref e) => Some(e),
+
+            Error::ServerHashNotFound(ref e) |
+            Error::ServerFormatNotFound(ref e) |
+            Error::ServerHashDependenciesNotFound(ref e) |
+            Error::ServerInfoNotFound(ref e) => Some(e),
+
+            Error::DeviceKeyNotFound(ref e) => Some(e),
+            _ => None
+        }
+    }
 }
 
 
 // Shorthand for Results originating in this module
-type Result<T> = std::result::Result<T, Error>;
+pub type Result<T> = std::result::Result<T, Error>;
 
-// Path to the client's root hash
+// Path to the client's root hash within the server's profile, stored after the version
+// of the tree's format.
+// Only the hash is stored if the format is `TreeFormat::V1`.
 const ROOT_HASH_PATH: &'static str = "root_hash";
 
 
-/// Verify that a file has not been modified. Returns `Ok` if that's the case, `Err` otherwise
-pub fn verify_file(client: &Client, file: FileID, data: &[u8]) -> Result<()> {
-    let client_root_hash = get_client_root_hash(client)?;
+/// Verify that a file has not been modified, given the hash of its leaf (see `leaf_hasher`).
+/// Returns `Ok` if that's the case, `Err` otherwise
+pub fn verify_file(server: &Server, file: FileID, leaf: &Hash) -> Result<()> {
+    let (format, client_root_hash) = get_client_root_hash(server)?;
 
-    verify_root_hashes(client, client_root_hash.clone())?;
+    verify_root_hashes(server, client_root_hash.clone())?;
 
-    let dependencies = get_file_dependencies(client, file)?;
-    let file_hash = hash(data);
+    let proof = get_file_proof(server, format, file)?;
 
-    let root_hash = MerkleTree::reconstruct_root_hash(dependencies, file as usize, file_hash);
+    check_proof(&proof, leaf, &client_root_hash)
+}
 
-    if root_hash == client_root_hash {
-        Ok(())
-    } else {
-        println!("client: {:#?}", client_root_hash);
-        println!("reconstructed: {:#?}", root_hash);
 
-        Err(Error::TamperedFiles)
+/// Returns true if the client's tree splits files into chunks, which can be verified on their own
+pub fn supports_chunk_proofs(server: &Server) -> Result<bool> {
+    let (format, _) = get_client_root_hash(server)?;
+
+    Ok(format.supports_chunk_proofs())
+}
+
+
+/// Download the proof of a run of chunks of a file, which also tells the length of the file
+pub fn get_chunk_proof(server: &Server, file: FileID, first: u64, count: u64) -> Result<ChunkProof> {
+    let (format, _) = get_client_root_hash(server)?;
+
+    if !format.supports_chunk_proofs() {
+        return Err(Error::ChunksUnsupported(format));
+    }
+
+    let proof = match download(server, &routes::verify_chunks(file, first, count)) {
+        Ok(bytes) => ChunkProof::from_bytes(&bytes).map_err(Error::InvalidProof)?,
+        Err(e) => return Err(Error::ServerHashDependenciesNotFound(e)),
+    };
+
+    let expected: Vec<u64> = (first..first + count).collect();
+
+    if proof.format() != format || proof.chunks() != expected {
+        return Err(Error::ProofMismatch);
+    }
+
+    Ok(proof)
+}
+
+
+/// Verify that a run of chunks of a file, given in order, has not been modified.
+/// Returns `Ok` if that's the case, `Err` otherwise
+pub fn verify_chunks(server: &Server, file: FileID, proof: &ChunkProof, chunks: &[&[u8]]) -> Result<()> {
+    let (format, client_root_hash) = get_client_root_hash(server)?;
+
+    verify_root_hashes(server, client_root_hash.clone())?;
+
+    let file_proof = get_file_proof(server, format, file)?;
+
+    let leaf = match proof.leaf(chunks) {
+        Ok(leaf) => leaf,
+
+        // The chunks are not the length the proof claims
+        Err(ProofError::InvalidChunk) => return Err(Error::TamperedFiles),
+
+        Err(e) => return Err(Error::InvalidProof(e))
+    };
+
+    check_proof(&file_proof, &leaf, &client_root_hash)
+}
+
+
+/// Start hashing the contents of a file into a leaf of the client's tree, so that files
+/// too large for memory can be hashed as they are read
+pub fn leaf_hasher(server: &Server) -> Result<LeafHasher> {
+    let (format, _) = get_client_root_hash(server)?;
+
+    Ok(format.leaf_hasher())
+}
+
+
+/// Verify that several files have not been modified, using a single proof for as many of them
+/// as the server takes at once. A file may be given more than once, but only with the same
+/// contents. Returns `Ok` if that's the case, `Err` otherwise
+pub fn verify_files(server: &Server, files: &[(FileID, &[u8])]) -> Result<()> {
+    let (format, client_root_hash) = get_client_root_hash(server)?;
+
+    let mut leaves = BTreeMap::new();
+
+    for &(file, data) in files {
+        let leaf = format.leaf(data);
+
+        // At most one of two different contents can be what the server stores
+        if leaves.insert(file, leaf.clone()).map_or(false, |other| other != leaf) {
+            return Err(Error::TamperedFiles);
+        }
+    }
+
+    let leaves: Vec<(FileID, Hash)> = leaves.into_iter().collect();
+
+    for batch in leaves.chunks(protocol::MAX_BATCH_IDS) {
+        verify_batch(server, format, &client_root_hash, batch)?;
+    }
+
+    Ok(())
+}
+
+/// Verify the leaves of some files with a single proof. The files must be in ascending order,
+/// and every file ID must be unique.
+fn verify_batch(server: &Server, format: TreeFormat, client_root_hash: &Hash, files: &[(FileID, Hash)]) -> Result<()> {
+    let ids: Vec<FileID> = files.iter().map(|&(file, _)| file).collect();
+
+    let proof = match exchange(server, routes::VERIFY_BATCH, protocol::encode_ids(&ids)) {
+        Ok(bytes) => MultiProof::from_bytes(&bytes).map_err(Error::InvalidProof)?,
+        Err(e) => return Err(Error::ServerHashDependenciesNotFound(e)),
+    };
+
+    let expected: Vec<u64> = ids.iter().map(|&file| file as u64).collect();
+
+    if proof.format() != format || proof.depth() != FILE_ID_BITS || proof.indices() != &expected[..] {
+        return Err(Error::ProofMismatch);
+    }
+
+    // The proof expects the leaves in the order of its indices, which is the order of the files
+    let leaves: Vec<Hash> = files.iter()
+        .map(|&(_, ref leaf)| leaf.clone())
+        .collect();
+
+    match proof.verify(&leaves, client_root_hash) {
+        Ok(()) => Ok(()),
+
+        // Only ask for the server's root hash if something is wrong, to tell
+        // an outdated client from tampered files
+        Err(ProofError::RootMismatch) => {
+            verify_root_hashes(server, client_root_hash.clone())?;
+            Err(Error::TamperedFiles)
+        }
+
+        Err(e) => Err(Error::InvalidProof(e))
+    }
+}
+
+
+/// Verify that the server does not store a file at some ID. Returns `Ok` if that's the case,
+/// `Err` otherwise
+pub fn verify_absent(server: &Server, file: FileID) -> Result<()> {
+    let (format, client_root_hash) = get_client_root_hash(server)?;
+
+    if !format.supports_absence_proofs() {
+        return Err(Error::InsecureTreeFormat(format));
     }
+
+    verify_root_hashes(server, client_root_hash.clone())?;
+
+    let proof = match download(server, &routes::verify_absent(file)) {
+        Ok(bytes) => parse_proof(&bytes, format, file)?,
+
+        // The server refuses to prove what it claimed
+        Err(super::Error::Http(StatusCode::NOT_FOUND)) => return Err(Error::TamperedFiles),
+
+        Err(e) => return Err(Error::ServerHashDependenciesNotFound(e)),
+    };
+
+    check_proof(&proof, &format.empty_leaf(), &client_root_hash)
+}
+
+
+/// Update the client's root hash, once the server has accepted a change leading to it.
+/// The server may have been changed again since, so it's not asked for its root hash.
+pub fn update_root_hash(server: &Server, client_root_hash: Hash) -> Result<()> {
+    let (format, _) = get_client_root_hash(server)?;
+
+    save_client_root_hash(server, format, client_root_hash)
+}
+
+
+/// Verify that the client's and the server's root hashes match. Returns `Ok` if that's
+/// the case, `Err` otherwise
+pub fn verify_root_hash(server: &Server) -> Result<()> {
+    let (_, client_root_hash) = get_client_root_hash(server)?;
+
+    verify_root_hashes(server, client_root_hash)
+}
+
+
+/// Returns the client's root hash, loading it from the server's profile, or
+/// downloading it from the server the first time
+pub fn load_root_hash(server: &Server) -> Result<Hash> {
+    get_client_root_hash(server).map(|(_, hash)| hash)
+}
+
+
+/// Download what the server supports, and check that this client can verify it
+pub fn check_server_info(server: &Server) -> Result<Info> {
+    let info = match download(server, routes::INFO) {
+        Ok(bytes) => Info::from_bytes(&bytes)
+            .ok_or(Error::ServerInfoNotFound(super::Error::InvalidResponse))?,
+        Err(e) => return Err(Error::ServerInfoNotFound(e)),
+    };
+
+    let format = info.check().map_err(Error::IncompatibleServer)?;
+
+    // A profile which has not saved a root hash yet takes whatever format the server has
+    if let Some((saved, _)) = read_client_root_hash(server)? {
+        if saved != format {
+            return Err(Error::IncompatibleServer(Incompatibility::FormatChanged {
+                saved,
+                server: format,
+            }));
+        }
+    }
+
+    Ok(info)
+}
+
+
+/// Download the server's current root hash
+pub fn fetch_root_hash(server: &Server) -> Result<Hash> {
+    get_server_root_hash(server)
+}
+
+
+/// Move the client's root hash forward to the server's, after verifying that the server's
+/// tree was reached by changing leaves of the client's tree, and that every change was
+/// signed by this device or one it trusts. Returns the changes, in the order they were made.
+///
+/// If any change can't be trusted the client's root hash is left as it is, since the
+/// server's tree can't be reached without it.
+pub fn fast_forward_root_hash(server: &Server) -> Result<Vec<LeafChange>> {
+    let (format, client_root_hash) = get_client_root_hash(server)?;
+
+    let (signatures, proof) = match download(server, &routes::verify_changes(&client_root_hash)) {
+        Ok(bytes) => parse_signed_changes(&bytes)?,
+
+        // The server never had the client's root hash, or rewrote its history since
+        Err(super::Error::Http(StatusCode::NOT_FOUND)) => return Err(Error::InconsistentHistory),
+
+        Err(e) => return Err(Error::ServerHashDependenciesNotFound(e)),
+    };
+
+    for change in proof.changes() {
+        if change.proof.format() != format || change.proof.depth() != FILE_ID_BITS {
+            return Err(Error::ProofMismatch);
+        }
+    }
+
+    let devices = Devices::load().map_err(Error::DeviceKeyNotFound)?;
+
+    // The server may have changed further since the proof was made, so the
+    // new root hash is the one the changes lead to
+    let mut server_root_hash = client_root_hash;
+
+    for (change, signature) in proof.changes().iter().zip(&signatures) {
+        let index = change.proof.index();
+
+        let trusted = match *signature {
+            Some(ref signature) => devices.is_trusted(signature, &server_root_hash, index, &change.new),
+            None => false
+        };
+
+        if !trusted {
+            return Err(Error::UntrustedChange {
+                file: index,
+                before: server_root_hash
+            });
+        }
+
+        server_root_hash = match change.apply(&server_root_hash) {
+            Ok(hash) => hash,
+            Err(ProofError::RootMismatch) => return Err(Error::InconsistentHistory),
+            Err(e) => return Err(Error::InvalidProof(e))
+        };
+    }
+
+    save_client_root_hash(server, format, server_root_hash)?;
+
+    Ok(proof.changes().to_vec())
+}
+
+
+/// Sign a change to a file, to be made to a tree with the root hash `before`, so that
+/// other devices can tell that this device made it
+pub fn sign_change(file: FileID, before: &Hash, leaf: &Hash) -> Result<Vec<u8>> {
+    let devices = Devices::load().map_err(Error::DeviceKeyNotFound)?;
+
+    Ok(devices.sign_change(before, file as u64, leaf))
+}
+
+
+/// Sign the removal of a file, like `sign_change`
+pub fn sign_removal(server: &Server, file: FileID, before: &Hash) -> Result<Vec<u8>> {
+    let (format, _) = get_client_root_hash(server)?;
+
+    sign_change(file, before, &format.empty_leaf())
 }
 
 
-/// Update the client's root hash
-pub fn update_root_hash(client: &Client, client_root_hash: Hash) -> Result<()> {
-    verify_root_hashes(client, client_root_hash.clone())?;
+/// Returns this device's public key in hexadecimal, to be added to the trusted devices of
+/// other devices sharing the server
+pub fn device_public_key() -> Result<String> {
+    let devices = Devices::load().map_err(Error::DeviceKeyNotFound)?;
 
-    save_client_root_hash(client_root_hash)
+    Ok(protocol::to_hex(devices.public_key()))
 }
 
 
-/// Compute a new root hash based on a files location and it's expected data
-pub fn compute_new_root_hash(client: &Client, file: FileID, data: &[u8]) -> Result<Hash> {
-    let client_root_hash = get_client_root_hash(client)?; 
-    verify_root_hashes(client, client_root_hash)?;
+/// Compute a new root hash based on a files location and the hash of it's expected leaf.
+/// Returns the client's root hash, which the server must still have when the file is
+/// changed, and the new root hash.
+pub fn compute_new_root_hash(server: &Server, file: FileID, leaf: &Hash) -> Result<(Hash, Hash)> {
+    let (format, client_root_hash) = get_client_root_hash(server)?;
+    verify_root_hashes(server, client_root_hash.clone())?;
 
-    let dependencies = get_file_dependencies(client, file)?;
+    let proof = get_file_proof(server, format, file)?;
 
-    let file_hash = hash(data);
-    let root_hash = MerkleTree::reconstruct_root_hash(dependencies, file as usize, file_hash);
+    let new_root_hash = proof.root(leaf).map_err(Error::InvalidProof)?;
+    Ok((client_root_hash, new_root_hash))
+}
+
+
+/// Compute a new root hash based on a files location, once the file has been removed.
+/// Returns the same hashes as `compute_new_root_hash`.
+pub fn compute_removed_root_hash(server: &Server, file: FileID) -> Result<(Hash, Hash)> {
+    let (format, _) = get_client_root_hash(server)?;
 
-    Ok(root_hash)
+    compute_new_root_hash(server, file, &format.empty_leaf())
 }
 
 
 /// Verify that this client's and the server's root hashes match
-fn verify_root_hashes(client: &Client, client_root_hash: Hash) -> Result<()> {
-    let server_root_hash = get_server_root_hash(client)?;
+fn verify_root_hashes(server: &Server, client_root_hash: Hash) -> Result<()> {
+    let server_root_hash = get_server_root_hash(server)?;
 
     if client_root_hash != server_root_hash {
         Err(Error::HashOutOfDate{client: client_root_hash, server: server_root_hash})
@@ -120,77 +504,129 @@ fn verify_root_hashes(client: &Client, client_root_hash: Hash) -> Result<()> {
 }
 
 
-/// Return the hashes required to reconstruct the root hash from a specific file
-fn get_file_dependencies(client: &Client, file: FileID) -> Result<Vec<Hash>> {
-    match download(client, &verify_file_uri(file)) {
-        Ok(bytes) => {
-            let hash_count = bytes.len() / Hash::BYTES;
+/// Return the proof required to reconstruct the root hash from a specific file
+fn get_file_proof(server: &Server, format: TreeFormat, file: FileID) -> Result<MerkleProof> {
+    match download(server, &routes::verify_file(file)) {
+        Ok(bytes) => parse_proof(&bytes, format, file),
+        Err(e) => Err(Error::ServerHashDependenciesNotFound(e)),
+    }
+}
 
-            if hash_count != mem::size_of::<FileID>() * 8 {
-                return Err(Error::InvalidHashDependencyCount(hash_count));
-            }
+/// Decode the signatures of the changes made on the server, each prefixed by its length,
+/// followed by the proof of the changes. There must be a signature, or an empty one, for
+/// every change.
+fn parse_signed_changes(bytes: &[u8]) -> Result<(Vec<Option<Vec<u8>>>, ConsistencyProof)> {
+    let (signatures, proof) = protocol::decode_signed_changes(bytes).map_err(Error::InvalidProof)?;
 
-            let mut hashes = Vec::new();
-            hashes.reserve(hash_count);
+    if proof.changes().len() != signatures.len() {
+        return Err(Error::ProofMismatch);
+    }
 
-            for i in 0..hash_count {
-                let low = i * Hash::BYTES;
-                let high = low + Hash::BYTES;
+    Ok((signatures, proof))
+}
 
-                hashes.push(Hash::from_bytes(&bytes[low..high]))
-            }
+/// Decode a proof, and make sure it belongs to the expected file and tree
+fn parse_proof(bytes: &[u8], format: TreeFormat, file: FileID) -> Result<MerkleProof> {
+    let proof = MerkleProof::from_bytes(bytes).map_err(Error::InvalidProof)?;
 
-            Ok(hashes)
-        }
+    if proof.format() != format || proof.depth() != FILE_ID_BITS || proof.index() != file as u64 {
+        return Err(Error::ProofMismatch);
+    }
 
-        Err(e) => Err(Error::ServerHashDependenciesNotFound(e)),
+    Ok(proof)
+}
+
+/// Verify that a leaf reconstructs the client's root hash
+fn check_proof(proof: &MerkleProof, leaf: &Hash, client_root_hash: &Hash) -> Result<()> {
+    match proof.verify(leaf, client_root_hash) {
+        Ok(()) => Ok(()),
+        Err(ProofError::RootMismatch) => Err(Error::TamperedFiles),
+        Err(e) => Err(Error::InvalidProof(e))
     }
 }
 
 
 /// Attempts to download the server's root hash
-fn get_server_root_hash(client: &Client) -> Result<Hash> {
-    match download(client, &root_hash_uri()) {
-        Ok(bytes) => Ok(Hash::from_bytes(&bytes)),
+fn get_server_root_hash(server: &Server) -> Result<Hash> {
+    match download(server, routes::VERIFY_ROOT) {
+        Ok(bytes) => protocol::decode_root(&bytes)
+            .ok_or(Error::ServerHashNotFound(super::Error::InvalidResponse)),
         Err(e) => Err(Error::ServerHashNotFound(e)),
     }
 }
 
-/// Attempts to load the client's root hash.
+/// Attempts to download the format of the server's tree
+fn get_server_tree_format(server: &Server) -> Result<TreeFormat> {
+    let bytes = download(server, routes::VERIFY_FORMAT)
+        .map_err(Error::ServerFormatNotFound)?;
+
+    match protocol::decode_format(&bytes) {
+        Ok(format) => Ok(format),
+        Err(FormatError::UnknownVersion(version)) => Err(Error::UnknownTreeFormat(version)),
+        Err(FormatError::InvalidLength) => Err(Error::ServerFormatNotFound(super::Error::InvalidResponse)),
+    }
+}
+
+/// Attempts to load the client's root hash and the format of the tree.
 /// If the client does not have a root hash 
 /// a new one be downloaded from the server.
-fn get_client_root_hash(client: &Client) -> Result<Hash> {
-    match File::open(ROOT_HASH_PATH) {
+fn get_client_root_hash(server: &Server) -> Result<(TreeFormat, Hash)> {
+    match read_client_root_hash(server)? {
+        Some(saved) => Ok(saved),
+        None => initialize_client_hash(server)
+    }
+}
+
+/// Read the client's root hash and the format of its tree, if the profile has saved them
+fn read_client_root_hash(server: &Server) -> Result<Option<(TreeFormat, Hash)>> {
+    match File::open(server.profile_path(ROOT_HASH_PATH)) {
         Ok(mut file) => {
-            let mut hash = Hash::default();
+            let mut bytes = Vec::new();
 
-            match file.read_exact(hash.as_bytes_mut()) {
-                Ok(_) => Ok(hash),
-                Err(e) => Err(Error::ClientHashInvalid(e))
+            if let Err(e) = file.read_to_end(&mut bytes) {
+                return Err(Error::ClientHashInvalid(e));
             }
-        },
 
-        Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
-            initialize_client_hash(client)
+            let (format, hash) = match bytes.len() {
+                // Saved before the format was stored
+                len if len == Hash::BYTES => (TreeFormat::V1, &bytes[..]),
+
+                len if len == Hash::BYTES + 1 => match TreeFormat::from_version(bytes[0]) {
+                    Some(format) => (format, &bytes[1..]),
+                    None => return Err(Error::UnknownTreeFormat(bytes[0]))
+                },
+
+                _ => return Err(Error::ClientHashInvalid(
+                    io::Error::new(io::ErrorKind::InvalidData, "Invalid root hash length")
+                ))
+            };
+
+            Ok(Some((format, Hash::from_bytes(hash))))
         },
 
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+
         Err(e) => Err(Error::ClientHashNotFound(e))
     }
 }
 
-/// Downloads the root hash of the server and saves it to the client
-fn initialize_client_hash(client: &Client) -> Result<Hash> {
-    let hash = get_server_root_hash(client)?;
-    save_client_root_hash(hash.clone())?;
-    Ok(hash)
+/// Downloads the root hash and tree format of the server and saves them to the client
+fn initialize_client_hash(server: &Server) -> Result<(TreeFormat, Hash)> {
+    let format = get_server_tree_format(server)?;
+    let hash = get_server_root_hash(server)?;
+    save_client_root_hash(server, format, hash.clone())?;
+    Ok((format, hash))
 }
 
 
 /// Attempts to save the client's root hash
-fn save_client_root_hash(hash: Hash) -> Result<()> {
-    match File::create(ROOT_HASH_PATH) {
+fn save_client_root_hash(server: &Server, format: TreeFormat, hash: Hash) -> Result<()> {
+    match File::create(server.profile_path(ROOT_HASH_PATH)) {
         Ok(mut file) => {
-            match file.write(hash.as_bytes()) {
+            let mut bytes = vec![format.version()];
+            bytes.extend_from_slice(hash.as_bytes());
+
+            match file.write_all(&bytes) {
                 Ok(_) => Ok(()),
                 Err(e) => Err(Error::ClientHashNoWrite(e))
             }
@@ -199,13 +635,3 @@ fn save_client_root_hash(hash: Hash) -> Result<()> {
         Err(e) => Err(Error::ClientHashNotFound(e)),
     }
 }
-
-/// Get the URI to the verification hashes for a file on the server
-fn verify_file_uri(file: FileID) -> String {
-    format!("http://localhost:8000/file/verify/{id}", id = file)
-}
-
-/// Get the URI to the top hash on the server
-fn root_hash_uri() -> String {
-    "http://localhost:8000/file/verify/root".to_owned()
-}
@@ -0,0 +1,93 @@
+use std::{
+    self,
+    fmt,
+    io,
+};
+
+use communication::{
+    self,
+    config,
+    secret,
+    verification,
+};
+
+
+/// Everything that can keep the client from completing an operation
+#[derive(Debug)]
+pub enum Error {
+    /// The server's profile could not be opened, or belongs to another server
+    Config(config::Error),
+
+    /// A request to the server failed
+    Communication(communication::Error),
+
+    /// The server's files, proofs or history could not be verified
+    Verification(verification::Error),
+
+    /// A file could not be encrypted or decrypted
+    Secret(secret::Error),
+
+    /// A local file could not be read or written
+    Io(io::Error),
+
+    /// Other writers kept changing the server's tree, so a change was given up
+    TooManyAttempts,
+}
+
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Config(ref e) => write!(f, "{}", e),
+            Error::Communication(ref e) => write!(f, "{}", e),
+            Error::Verification(ref e) => write!(f, "{}", e),
+            Error::Secret(ref e) => write!(f, "{}", e),
+            Error::Io(ref e) => write!(f, "{}", e),
+            Error::TooManyAttempts => write!(f, "other writers kept changing the server's tree, so the change was given up"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(std::error::Error + 'static)> {
+        match *self {
+            Error::Config(ref e) => Some(e),
+            Error::Communication(ref e) => Some(e),
+            Error::Verification(ref e) => Some(e),
+            Error::Secret(ref e) => Some(e),
+            Error::Io(ref e) => Some(e),
+            _ => None
+        }
+    }
+}
+
+
+impl From<config::Error> for Error {
+    fn from(error: config::Error) -> Error {
+        Error::Config(error)
+    }
+}
+
+impl From<communication::Error> for Error {
+    fn from(error: communication::Error) -> Error {
+        Error::Communication(error)
+    }
+}
+
+impl From<verification::Error> for Error {
+    fn from(error: verification::Error) -> Error {
+        Error::Verification(error)
+    }
+}
+
+impl From<secret::Error> for Error {
+    fn from(error: secret::Error) -> Error {
+        Error::Secret(error)
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(error: io::Error) -> Error {
+        Error::Io(error)
+    }
+}
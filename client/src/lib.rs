@@ -0,0 +1,46 @@
+//! A client for the file storage server. Files are encrypted before they are
+//! uploaded, and every file, proof and change the server sends back is verified
+//! against the root hash of the server's Merkle tree, which the client keeps.
+
+extern crate reqwest;
+extern crate ring;
+extern crate argon2;
+extern crate chacha20poly1305;
+extern crate tempfile;
+extern crate untrusted;
+
+extern crate file_hash;
+extern crate protocol;
+
+mod communication;
+mod error;
+mod storage_client;
+
+pub use error::Error;
+pub use storage_client::StorageClient;
+
+pub use communication::{
+    Cipher,
+    Config,
+    DEFAULT_SERVER,
+};
+
+// The errors combined in `Error`
+pub use communication::{
+    Error as CommunicationError,
+    config::Error as ConfigError,
+    device::Error as DeviceError,
+    secret::Error as SecretError,
+    verification::Error as VerificationError,
+};
+
+pub use file_hash::LeafChange;
+
+pub use protocol::{
+    FileID,
+    Hash,
+    Incompatibility,
+    Info,
+    ProofError,
+    TreeFormat,
+};
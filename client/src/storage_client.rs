@@ -0,0 +1,130 @@
+use std::io::{
+    Read,
+    Write,
+};
+
+use file_hash::LeafChange;
+
+use protocol::{
+    FileID,
+    Hash,
+    Info,
+};
+
+use communication::{
+    self,
+    Config,
+    Server,
+};
+
+use error::Error;
+
+
+/// A client of a single server. Files are encrypted before they leave the client, and
+/// everything the server sends back is verified against the client's root hash.
+///
+/// No method panics: every failure, including a server that can't be trusted, is
+/// returned as an `Error`.
+pub struct StorageClient {
+    config: Config,
+    server: Server,
+    info: Info,
+}
+
+
+impl StorageClient {
+    /// Open the profile of the configured server, creating it if needed, and check that
+    /// the server is one this client is able to verify
+    pub fn new(config: Config) -> Result<StorageClient, Error> {
+        let server = Server::new(&config)?;
+        let info = communication::server_info(&server)?;
+
+        Ok(StorageClient {
+            config,
+            server,
+            info,
+        })
+    }
+
+
+    /// Returns the URL of the server
+    pub fn url(&self) -> &str {
+        self.server.url()
+    }
+
+    /// Returns the name of the server's profile
+    pub fn profile(&self) -> String {
+        self.config.profile_name()
+    }
+
+    /// Returns what the server supports, as it told when the client was opened
+    pub fn info(&self) -> &Info {
+        &self.info
+    }
+
+
+    /// Encrypt everything read from `reader` and upload it as a file
+    pub fn upload<R: Read>(&self, file: FileID, password: &[u8], reader: &mut R) -> Result<(), Error> {
+        communication::upload_stream(reader, file, password, &self.server)
+    }
+
+    /// Encrypt some bytes and upload them as a file
+    pub fn upload_bytes(&self, file: FileID, password: &[u8], data: &[u8]) -> Result<(), Error> {
+        communication::upload_file(data, file, password, &self.server)
+    }
+
+
+    /// Download, verify and decrypt a file, writing its contents to `writer`. Returns
+    /// `false` if the server proves that the file does not exist.
+    pub fn download<W: Write>(&self, file: FileID, password: &[u8], writer: &mut W) -> Result<bool, Error> {
+        communication::download_stream(file, password, &self.server, writer)
+    }
+
+    /// Download, verify and decrypt a file. Returns `None` if the server proves that
+    /// the file does not exist.
+    pub fn download_bytes(&self, file: FileID, password: &[u8]) -> Result<Option<Vec<u8>>, Error> {
+        communication::download_file(file, password, &self.server)
+    }
+
+    /// Download, verify and decrypt up to `length` bytes of a file, starting at `offset`
+    pub fn download_range(&self, file: FileID, password: &[u8], offset: u64, length: u64) -> Result<Option<Vec<u8>>, Error> {
+        communication::download_range(file, offset, length, password, &self.server)
+    }
+
+    /// Download several files, verify all of them at once and decrypt them
+    pub fn download_many(&self, files: &[FileID], password: &[u8]) -> Result<Vec<Vec<u8>>, Error> {
+        communication::download_files(files, password, &self.server)
+    }
+
+
+    /// Download a file and verify it, without decrypting it. Returns `false` if the
+    /// server proves that the file does not exist.
+    pub fn verify(&self, file: FileID) -> Result<bool, Error> {
+        communication::verify_stored_file(file, &self.server)
+    }
+
+
+    /// Remove a file. Returns `false` if the server proves that the file did not exist.
+    pub fn delete(&self, file: FileID) -> Result<bool, Error> {
+        communication::delete_file(file, &self.server)
+    }
+
+
+    /// Accept the changes made on the server by trusted devices since the client last
+    /// saw it. Returns the changes.
+    pub fn synchronize(&self) -> Result<Vec<LeafChange>, Error> {
+        communication::synchronize(&self.server)
+    }
+
+
+    /// Returns the client's root hash and the server's
+    pub fn root_hashes(&self) -> Result<(Hash, Hash), Error> {
+        communication::root_hashes(&self.server)
+    }
+
+
+    /// Returns the public key this device signs its changes with, in hexadecimal
+    pub fn device_key(&self) -> Result<String, Error> {
+        communication::device_key()
+    }
+}
@@ -0,0 +1,317 @@
+use std::{
+    self,
+    io::{
+        self,
+        Read
+    }
+};
+
+use super::{
+    Hash,
+    MerkleTree,
+    MultiProof,
+    ProofError,
+    TreeFormat,
+    CHUNK_SIZE
+};
+
+use encoding::{
+    Reader,
+    to_big_endian
+};
+
+use multi_proof::position;
+
+
+/// The tree of a file's chunks, whose root makes up the file's leaf in
+/// `TreeFormat::V3`.
+///
+/// The chunks are laid out from left to right, so a run of chunks shares most
+/// of its siblings. The tree is as shallow as possible, with empty slots to the
+/// right of the last chunk. A file always has at least one chunk, which is
+/// empty for an empty file.
+#[derive(Debug)]
+pub struct ChunkTree {
+    tree: MerkleTree,
+    length: u64
+}
+
+
+/// The hashes required to reconstruct the leaf of a file from some of its
+/// chunks.
+///
+/// Encoded as the length of the file as a big-endian 64-bit integer, followed
+/// by the `MultiProof` of the chunks in the file's `ChunkTree`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ChunkProof {
+    length: u64,
+    proof: MultiProof
+}
+
+
+type Result<T> = std::result::Result<T, ProofError>;
+
+
+impl ChunkTree {
+    /// Build the tree from the length of a file and the hashes of its chunks
+    pub fn new(format: TreeFormat, length: u64, chunks: &[Hash]) -> ChunkTree {
+        let depth = chunk_depth(length);
+        let mut tree = MerkleTree::new(depth, format);
+
+        for (chunk, hash) in chunks.iter().enumerate() {
+            tree.insert(position(chunk as u64, depth), hash.clone()).unwrap();
+        }
+
+        ChunkTree {
+            tree,
+            length
+        }
+    }
+
+
+    /// Build the tree of a whole file, read a chunk at a time
+    pub fn read<R: Read>(format: TreeFormat, reader: &mut R) -> io::Result<ChunkTree> {
+        if !format.supports_chunk_proofs() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{:?} does not split files into chunks", format)
+            ));
+        }
+
+        let mut hasher = format.leaf_hasher();
+        io::copy(reader, &mut hasher)?;
+
+        Ok(hasher.finish_chunks())
+    }
+
+
+    /// Returns the length of the file in bytes
+    pub fn length(&self) -> u64 {
+        self.length
+    }
+
+    /// Returns the number of chunks in the file
+    pub fn chunk_count(&self) -> u64 {
+        chunk_count(self.length)
+    }
+
+
+    /// Returns the leaf of the file
+    pub fn leaf(&self) -> Hash {
+        self.tree.format().file(self.length, &self.tree.root())
+    }
+
+
+    /// Returns a proof that a run of chunks is part of the file, or `None` if
+    /// the run is empty or goes past the last chunk
+    pub fn proof(&self, first: u64, count: u64) -> Option<ChunkProof> {
+        if count == 0 || first >= self.chunk_count() || count > self.chunk_count() - first {
+            return None;
+        }
+
+        let depth = self.tree.depth();
+        let indices: Vec<u64> = (first..first + count)
+            .map(|chunk| position(chunk, depth))
+            .collect();
+
+        self.tree.multi_proof(&indices).ok()
+            .map(|proof| ChunkProof {
+                length: self.length,
+                proof
+            })
+    }
+}
+
+
+impl ChunkProof {
+    /// Returns the length of the file in bytes
+    pub fn length(&self) -> u64 {
+        self.length
+    }
+
+    pub fn format(&self) -> TreeFormat {
+        self.proof.format()
+    }
+
+    /// Returns the indices of the chunks, in the order their data is expected
+    pub fn chunks(&self) -> Vec<u64> {
+        let depth = self.proof.depth();
+
+        let mut chunks: Vec<u64> = self.proof.indices().iter()
+            .map(|&index| position(index, depth))
+            .collect();
+
+        chunks.sort();
+        chunks
+    }
+
+
+    /// Reconstruct the leaf of the file from the data of its chunks, given in
+    /// the same order as `chunks`
+    pub fn leaf(&self, chunks: &[&[u8]]) -> Result<Hash> {
+        let format = self.proof.format();
+
+        if !format.supports_chunk_proofs() {
+            return Err(ProofError::ChunksUnsupported);
+        }
+
+        if self.proof.depth() != chunk_depth(self.length) {
+            return Err(ProofError::InvalidDepth);
+        }
+
+        let indices = self.chunks();
+
+        if chunks.len() != indices.len() {
+            return Err(ProofError::LeafCountMismatch);
+        }
+
+        for (&chunk, data) in indices.iter().zip(chunks) {
+            if chunk_length(self.length, chunk) != Some(data.len() as u64) {
+                return Err(ProofError::InvalidChunk);
+            }
+        }
+
+        // The proof expects the hashes in the order of the tree's indices
+        let depth = self.proof.depth();
+        let hashes: Vec<Hash> = self.proof.indices().iter()
+            .map(|&index| {
+                let chunk = position(index, depth);
+                let i = indices.binary_search(&chunk).unwrap();
+                format.chunk(chunks[i])
+            })
+            .collect();
+
+        let root = self.proof.root(&hashes)?;
+        Ok(format.file(self.length, &root))
+    }
+
+
+    /// Verify that the chunks are part of the file with the leaf
+    pub fn verify(&self, chunks: &[&[u8]], leaf: &Hash) -> Result<()> {
+        if self.leaf(chunks)? == *leaf {
+            Ok(())
+        } else {
+            Err(ProofError::RootMismatch)
+        }
+    }
+
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = to_big_endian(self.length, 8);
+        bytes.extend_from_slice(&self.proof.to_bytes());
+        bytes
+    }
+
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<ChunkProof> {
+        let mut reader = Reader(bytes);
+        let length = reader.u64().ok_or(ProofError::InvalidLength)?;
+
+        Ok(ChunkProof {
+            length,
+            proof: MultiProof::from_bytes(reader.0)?
+        })
+    }
+}
+
+
+/// Returns the number of chunks in a file of some length
+pub fn chunk_count(length: u64) -> u64 {
+    let partial = if length % CHUNK_SIZE == 0 { 0 } else { 1 };
+
+    std::cmp::max(1, length / CHUNK_SIZE + partial)
+}
+
+
+/// Returns the length of a chunk in a file of some length, or `None` if the
+/// file has no such chunk
+pub fn chunk_length(length: u64, chunk: u64) -> Option<u64> {
+    if chunk >= chunk_count(length) {
+        None
+    } else {
+        Some(std::cmp::min(CHUNK_SIZE, length - chunk * CHUNK_SIZE))
+    }
+}
+
+
+/// Returns the depth of the `ChunkTree` of a file of some length
+fn chunk_depth(length: u64) -> u8 {
+    64 - (chunk_count(length) - 1).leading_zeros() as u8
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn example_file(length: usize) -> Vec<u8> {
+        (0..length).map(|i| (i * 7) as u8).collect()
+    }
+
+    #[test]
+    fn leaf_matches_hasher() {
+        let format = TreeFormat::V3;
+
+        for &length in &[0, 1, CHUNK_SIZE as usize, CHUNK_SIZE as usize + 1, 5 * CHUNK_SIZE as usize - 3] {
+            let file = example_file(length);
+            let tree = ChunkTree::read(format, &mut &file[..]).unwrap();
+
+            assert_eq!(length as u64, tree.length());
+            assert_eq!(format.leaf(&file), tree.leaf());
+
+            let mut hasher = format.leaf_hasher();
+            for part in file.chunks(1000) {
+                hasher.update(part);
+            }
+
+            assert_eq!(tree.leaf(), hasher.finish());
+        }
+
+        assert_ne!(format.leaf(&[]), format.leaf(&[0]));
+    }
+
+    #[test]
+    fn verify_and_encode() {
+        let format = TreeFormat::V3;
+        let file = example_file(5 * CHUNK_SIZE as usize - 3);
+        let tree = ChunkTree::read(format, &mut &file[..]).unwrap();
+        let chunks: Vec<&[u8]> = file.chunks(CHUNK_SIZE as usize).collect();
+
+        for &(first, count) in &[(0, 1), (4, 1), (1, 3), (0, 5)] {
+            let proof = tree.proof(first, count).unwrap();
+            let data = &chunks[first as usize..(first + count) as usize];
+
+            assert_eq!((first..first + count).collect::<Vec<_>>(), proof.chunks());
+            assert_eq!(Ok(()), proof.verify(data, &tree.leaf()));
+            assert_eq!(Ok(proof.clone()), ChunkProof::from_bytes(&proof.to_bytes()));
+
+            let mut tampered = data.to_vec();
+            let mut chunk = tampered[0].to_vec();
+            chunk[0] ^= 1;
+            tampered[0] = &chunk;
+            assert_eq!(Err(ProofError::RootMismatch), proof.verify(&tampered, &tree.leaf()));
+        }
+
+        // The last chunk can't be cut short
+        let proof = tree.proof(4, 1).unwrap();
+        assert_eq!(Err(ProofError::InvalidChunk), proof.verify(&[&chunks[4][1..]], &tree.leaf()));
+
+        assert!(tree.proof(5, 1).is_none());
+        assert!(tree.proof(4, 2).is_none());
+        assert!(tree.proof(0, 0).is_none());
+    }
+
+    #[test]
+    fn chunks_need_chunk_format() {
+        let file = example_file(10);
+        let proof = ChunkTree::read(TreeFormat::V3, &mut &file[..]).unwrap()
+            .proof(0, 1)
+            .unwrap();
+
+        let mut bytes = proof.to_bytes();
+        bytes[8] = TreeFormat::V2.version();
+
+        let proof = ChunkProof::from_bytes(&bytes).unwrap();
+        assert_eq!(Err(ProofError::ChunksUnsupported), proof.leaf(&[&file]));
+    }
+}
@@ -0,0 +1,247 @@
+use std;
+
+use super::{
+    Hash,
+    MerkleProof,
+    ProofError,
+    TreeFormat
+};
+
+use encoding::{
+    Reader,
+    to_big_endian
+};
+
+
+/// A single leaf of a tree being replaced.
+///
+/// Creating a file replaces an empty leaf, and removing one replaces it with
+/// an empty leaf.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct LeafChange {
+    /// The siblings of the leaf at the time of the change
+    pub proof: MerkleProof,
+
+    /// The hash of the leaf before the change
+    pub old: Hash,
+
+    /// The hash of the leaf after the change
+    pub new: Hash
+}
+
+
+/// Proves that a tree with one root hash was turned into a tree with another
+/// root hash by changing its leaves, one at a time.
+///
+/// Encoded as the number of changes as a big-endian 64-bit integer, followed
+/// by every change in order: the old and the new hash of the leaf and then
+/// the encoded `MerkleProof`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ConsistencyProof {
+    changes: Vec<LeafChange>
+}
+
+
+type Result<T> = std::result::Result<T, ProofError>;
+
+
+impl LeafChange {
+    /// Returns the root hash after the change, if the root hash was `before`
+    /// prior to it
+    pub fn apply(&self, before: &Hash) -> Result<Hash> {
+        self.proof.verify(&self.old, before)?;
+        self.proof.root(&self.new)
+    }
+
+
+    /// Returns true if the change created a file where there was none
+    pub fn is_creation(&self, format: TreeFormat) -> bool {
+        self.old == format.empty_leaf()
+    }
+
+    /// Returns true if the change removed a file
+    pub fn is_removal(&self, format: TreeFormat) -> bool {
+        self.new == format.empty_leaf()
+    }
+
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(self.old.as_bytes());
+        bytes.extend_from_slice(self.new.as_bytes());
+        bytes.extend_from_slice(&self.proof.to_bytes());
+        bytes
+    }
+
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<LeafChange> {
+        let mut reader = Reader(bytes);
+        let change = LeafChange::read(&mut reader)?;
+
+        if !reader.0.is_empty() {
+            return Err(ProofError::InvalidLength);
+        }
+
+        Ok(change)
+    }
+
+
+    /// Decode a change from the start of some bytes, returning it and the bytes
+    /// that follow it
+    pub fn from_prefix(bytes: &[u8]) -> Result<(LeafChange, &[u8])> {
+        let mut reader = Reader(bytes);
+        let change = LeafChange::read(&mut reader)?;
+
+        Ok((change, reader.0))
+    }
+
+
+    /// Read a change from the front of some bytes
+    pub(crate) fn read(reader: &mut Reader) -> Result<LeafChange> {
+        let old = reader.hash().ok_or(ProofError::InvalidLength)?;
+        let new = reader.hash().ok_or(ProofError::InvalidLength)?;
+        let proof = MerkleProof::read(reader)?;
+
+        Ok(LeafChange {
+            proof,
+            old,
+            new
+        })
+    }
+}
+
+
+impl ConsistencyProof {
+    pub fn new(changes: Vec<LeafChange>) -> ConsistencyProof {
+        ConsistencyProof {
+            changes
+        }
+    }
+
+
+    /// The changes, in the order they were made
+    pub fn changes(&self) -> &[LeafChange] {
+        &self.changes
+    }
+
+
+    /// Returns the root hash after all changes, if the root hash was `old`
+    /// before them
+    pub fn apply(&self, old: &Hash) -> Result<Hash> {
+        let mut root = old.clone();
+
+        for change in &self.changes {
+            root = change.apply(&root)?;
+        }
+
+        Ok(root)
+    }
+
+
+    /// Verify that all changes lead from the old root hash to the new one
+    pub fn verify(&self, old: &Hash, new: &Hash) -> Result<()> {
+        if self.apply(old)? == *new {
+            Ok(())
+        } else {
+            Err(ProofError::RootMismatch)
+        }
+    }
+
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = to_big_endian(self.changes.len() as u64, 8);
+
+        for change in &self.changes {
+            bytes.extend_from_slice(&change.to_bytes());
+        }
+
+        bytes
+    }
+
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<ConsistencyProof> {
+        let mut reader = Reader(bytes);
+
+        let count = reader.u64().ok_or(ProofError::InvalidLength)?;
+
+        let mut changes = Vec::new();
+        for _ in 0..count {
+            changes.push(LeafChange::read(&mut reader)?);
+        }
+
+        if !reader.0.is_empty() {
+            return Err(ProofError::InvalidLength);
+        }
+
+        Ok(ConsistencyProof {
+            changes
+        })
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use MerkleTree;
+
+    /// Change a leaf of a tree, recording the change
+    fn change(tree: &mut MerkleTree, node: u64, new: Option<&[u8]>) -> LeafChange {
+        let format = tree.format();
+        let proof = tree.proof(node).unwrap();
+        let old = tree.get(node).unwrap_or(format.empty_leaf());
+
+        let new = match new {
+            Some(data) => {
+                tree.insert(node, format.leaf(data)).unwrap();
+                format.leaf(data)
+            }
+
+            None => {
+                tree.remove(node).unwrap();
+                format.empty_leaf()
+            }
+        };
+
+        LeafChange {
+            proof,
+            old,
+            new
+        }
+    }
+
+    #[test]
+    fn verify_and_encode() {
+        let mut tree = MerkleTree::new(16, TreeFormat::default());
+        change(&mut tree, 7, Some(b"seven"));
+
+        let old_root = tree.root();
+
+        let changes = vec![
+            change(&mut tree, 1342, Some(b"first")),
+            change(&mut tree, 1342, Some(b"second")),
+            change(&mut tree, 7, None),
+            change(&mut tree, 9, Some(b"nine"))
+        ];
+
+        let proof = ConsistencyProof::new(changes);
+        assert_eq!(Ok(()), proof.verify(&old_root, &tree.root()));
+        assert_eq!(Ok(proof.clone()), ConsistencyProof::from_bytes(&proof.to_bytes()));
+        assert_eq!(Ok(proof.changes()[1].clone()), LeafChange::from_bytes(&proof.changes()[1].to_bytes()));
+
+        let mut suffixed = proof.changes()[2].to_bytes();
+        suffixed.extend_from_slice(b"rest");
+        assert_eq!(Ok((proof.changes()[2].clone(), &b"rest"[..])), LeafChange::from_prefix(&suffixed));
+
+        assert!(proof.changes()[0].is_creation(tree.format()));
+        assert!(proof.changes()[2].is_removal(tree.format()));
+
+        // Hiding a change breaks the chain
+        let mut hidden = proof.changes().to_vec();
+        hidden.remove(1);
+        assert!(ConsistencyProof::new(hidden).verify(&old_root, &tree.root()).is_err());
+
+        let bytes = proof.to_bytes();
+        assert_eq!(Err(ProofError::InvalidLength), ConsistencyProof::from_bytes(&bytes[..bytes.len() - 1]));
+    }
+}
@@ -0,0 +1,65 @@
+//! Helpers for the binary formats in this crate. All integers are big-endian.
+
+use super::Hash;
+
+
+/// Reads values from the front of a byte slice. Every method returns `None`
+/// if there are too few bytes left.
+pub(crate) struct Reader<'a>(pub(crate) &'a [u8]);
+
+impl<'a> Reader<'a> {
+    pub fn take(&mut self, count: usize) -> Option<&'a [u8]> {
+        if self.0.len() < count {
+            return None;
+        }
+
+        let (head, tail) = self.0.split_at(count);
+        self.0 = tail;
+        Some(head)
+    }
+
+    pub fn byte(&mut self) -> Option<u8> {
+        self.take(1).map(|bytes| bytes[0])
+    }
+
+    pub fn u64(&mut self) -> Option<u64> {
+        self.take(8).map(from_big_endian)
+    }
+
+    pub fn hash(&mut self) -> Option<Hash> {
+        self.take(Hash::BYTES).map(Hash::from_bytes)
+    }
+}
+
+
+/// Encode the lowest `width` bytes of an integer, most significant first.
+/// `width` is at most 8.
+pub fn to_big_endian(value: u64, width: usize) -> Vec<u8> {
+    (0..width).rev()
+        .map(|byte| (value >> (8 * byte)) as u8)
+        .collect()
+}
+
+/// Decode an integer of at most 8 bytes, most significant first
+pub fn from_big_endian(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0, |value, &byte| value << 8 | byte as u64)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn big_endian_round_trip() {
+        assert_eq!(vec![0x01, 0x02], to_big_endian(0x0102, 2));
+        assert_eq!(vec![0, 0, 0, 0, 0, 0, 0x01, 0x02], to_big_endian(0x0102, 8));
+        assert_eq!(Vec::<u8>::new(), to_big_endian(5, 0));
+
+        for &value in &[0, 1, 255, 256, u32::max_value() as u64, u64::max_value()] {
+            assert_eq!(value, from_big_endian(&to_big_endian(value, 8)));
+        }
+
+        assert_eq!(0x0203, from_big_endian(&to_big_endian(0x010203, 2)));
+    }
+}
@@ -0,0 +1,322 @@
+use std::{
+    self,
+    io::{
+        self,
+        Write
+    }
+};
+
+use ring::digest;
+
+use super::{
+    ChunkTree,
+    Hash,
+    hash
+};
+
+use encoding::to_big_endian;
+
+
+/// The number of bytes in every chunk of a file but the last, in `TreeFormat::V3`
+pub const CHUNK_SIZE: u64 = 64 * 1024;
+
+
+/// Computes the hash a file is stored as in a tree, without holding the
+/// whole file in memory. Feed it the file with `update` or through `Write`.
+pub struct LeafHasher {
+    format: TreeFormat,
+
+    /// Hashes the whole file, or only the current chunk in `V3`
+    context: digest::Context,
+
+    /// The hashes of the chunks before the current one, in `V3`
+    chunks: Vec<Hash>,
+
+    length: u64
+}
+
+
+/// The way leaves and branches of a `MerkleTree` are hashed
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum TreeFormat {
+    /// Leaves are the plain hash of a file, and branches the hash of both
+    /// children concatenated. A 64-byte file can therefore be mistaken for a
+    /// branch. Kept so that existing trees still verify.
+    V1,
+
+    /// Leaves, branches and empty slots are hashed with different prefixes,
+    /// as in RFC 6962, so that a file can never be mistaken for a branch or an
+    /// empty slot.
+    V2,
+
+    /// Hashed like `V2`, except that a file is split into chunks of
+    /// `CHUNK_SIZE` bytes and its leaf is the root of a tree of the chunks,
+    /// together with the length of the file. Parts of a file can therefore be
+    /// verified without the rest of it, see `ChunkProof`.
+    V3
+}
+
+
+const LEAF_PREFIX: u8 = 0x00;
+const BRANCH_PREFIX: u8 = 0x01;
+const EMPTY_PREFIX: u8 = 0x02;
+const FILE_PREFIX: u8 = 0x03;
+
+
+impl TreeFormat {
+    /// Returns the format with a specific version number
+    pub fn from_version(version: u8) -> Option<TreeFormat> {
+        match version {
+            1 => Some(TreeFormat::V1),
+            2 => Some(TreeFormat::V2),
+            3 => Some(TreeFormat::V3),
+            _ => None
+        }
+    }
+
+
+    /// Returns the version number of the format
+    pub fn version(&self) -> u8 {
+        match self {
+            &TreeFormat::V1 => 1,
+            &TreeFormat::V2 => 2,
+            &TreeFormat::V3 => 3
+        }
+    }
+
+
+    /// Returns the hash a file is stored as in a tree
+    pub fn leaf(&self, data: &[u8]) -> Hash {
+        match self {
+            &TreeFormat::V1 => hash(data),
+            &TreeFormat::V2 => prefixed_hash(LEAF_PREFIX, &[data]),
+
+            &TreeFormat::V3 => {
+                let mut hasher = self.leaf_hasher();
+                hasher.update(data);
+                hasher.finish()
+            }
+        }
+    }
+
+
+    /// Returns a hasher giving the same hash as `leaf` for a file fed to it in parts
+    pub fn leaf_hasher(&self) -> LeafHasher {
+        LeafHasher {
+            format: *self,
+            context: self.leaf_context(),
+            chunks: Vec::new(),
+            length: 0
+        }
+    }
+
+
+    /// Returns the hash of a chunk of a file in a tree of chunks
+    pub fn chunk(&self, data: &[u8]) -> Hash {
+        prefixed_hash(LEAF_PREFIX, &[data])
+    }
+
+
+    /// Returns the leaf of a file from its length and the root hash of its
+    /// tree of chunks
+    pub fn file(&self, length: u64, chunk_root: &Hash) -> Hash {
+        prefixed_hash(FILE_PREFIX, &[&to_big_endian(length, 8), chunk_root.as_bytes()])
+    }
+
+
+    /// Returns the hash of a branch from the hashes of its children
+    pub fn branch(&self, left: &Hash, right: &Hash) -> Hash {
+        match self {
+            &TreeFormat::V1 => left.clone().join(right.clone()),
+            &TreeFormat::V2 | &TreeFormat::V3 => {
+                prefixed_hash(BRANCH_PREFIX, &[left.as_bytes(), right.as_bytes()])
+            }
+        }
+    }
+
+
+    /// Returns the hash of an empty slot in a tree.
+    ///
+    /// In `V1` this is the same as a file containing `Hello, world!`, so
+    /// proving that a slot is empty is only possible in later formats.
+    pub fn empty_leaf(&self) -> Hash {
+        match self {
+            &TreeFormat::V1 => hash(b"Hello, world!"),
+            &TreeFormat::V2 | &TreeFormat::V3 => prefixed_hash(EMPTY_PREFIX, &[])
+        }
+    }
+
+
+    /// Returns true if an empty slot can't be mistaken for a file
+    pub fn supports_absence_proofs(&self) -> bool {
+        match self {
+            &TreeFormat::V1 => false,
+            &TreeFormat::V2 | &TreeFormat::V3 => true
+        }
+    }
+
+
+    /// Returns true if parts of a file can be verified on their own
+    pub fn supports_chunk_proofs(&self) -> bool {
+        match self {
+            &TreeFormat::V1 | &TreeFormat::V2 => false,
+            &TreeFormat::V3 => true
+        }
+    }
+
+
+    /// Starts hashing a file, or the first chunk of one in `V3`
+    fn leaf_context(&self) -> digest::Context {
+        let mut context = digest::Context::new(&digest::SHA256);
+
+        if *self != TreeFormat::V1 {
+            context.update(&[LEAF_PREFIX]);
+        }
+
+        context
+    }
+}
+
+
+impl Default for TreeFormat {
+    fn default() -> Self {
+        TreeFormat::V3
+    }
+}
+
+
+impl LeafHasher {
+    /// Hash the next part of the file
+    pub fn update(&mut self, mut data: &[u8]) {
+        if !self.format.supports_chunk_proofs() {
+            self.context.update(data);
+            return;
+        }
+
+        while !data.is_empty() {
+            // A full chunk is only finished once more data follows, since the
+            // last chunk is hashed by `finish`
+            if self.length > 0 && self.length % CHUNK_SIZE == 0 {
+                let context = std::mem::replace(&mut self.context, self.format.leaf_context());
+                self.chunks.push(Hash::from_bytes(context.finish().as_ref()));
+            }
+
+            let room = CHUNK_SIZE - self.length % CHUNK_SIZE;
+            let (chunk, rest) = data.split_at(std::cmp::min(room, data.len() as u64) as usize);
+
+            self.context.update(chunk);
+            self.length += chunk.len() as u64;
+            data = rest;
+        }
+    }
+
+    /// Return the hash of everything fed to the hasher
+    pub fn finish(self) -> Hash {
+        if self.format.supports_chunk_proofs() {
+            self.finish_chunks().leaf()
+        } else {
+            Hash::from_bytes(self.context.finish().as_ref())
+        }
+    }
+
+    /// Return the hash of everything fed to the hasher, together with the
+    /// hashes of its chunks in `V3`, from which `ChunkTree::new` rebuilds the
+    /// file's tree without reading it again. There are no chunks in other formats.
+    pub fn finish_with_chunks(mut self) -> (Hash, Vec<Hash>) {
+        if !self.format.supports_chunk_proofs() {
+            return (Hash::from_bytes(self.context.finish().as_ref()), Vec::new());
+        }
+
+        self.chunks.push(Hash::from_bytes(self.context.finish().as_ref()));
+        let leaf = ChunkTree::new(self.format, self.length, &self.chunks).leaf();
+
+        (leaf, self.chunks)
+    }
+
+    /// Return the tree of the chunks fed to the hasher, in `V3`
+    pub(crate) fn finish_chunks(mut self) -> ChunkTree {
+        self.chunks.push(Hash::from_bytes(self.context.finish().as_ref()));
+
+        ChunkTree::new(self.format, self.length, &self.chunks)
+    }
+}
+
+impl Write for LeafHasher {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        self.update(data);
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+
+fn prefixed_hash(prefix: u8, parts: &[&[u8]]) -> Hash {
+    let mut bytes = vec![prefix];
+
+    for part in parts {
+        bytes.extend_from_slice(part);
+    }
+
+    hash(&bytes)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn leaves_are_not_branches() {
+        let left = hash(b"left");
+        let right = hash(b"right");
+
+        let mut file = left.clone().into_vec();
+        file.extend_from_slice(right.as_bytes());
+
+        assert_eq!(TreeFormat::V1.leaf(&file), TreeFormat::V1.branch(&left, &right));
+        assert_ne!(TreeFormat::V2.leaf(&file), TreeFormat::V2.branch(&left, &right));
+    }
+
+    #[test]
+    fn files_are_not_empty_slots() {
+        let file = b"Hello, world!";
+
+        assert_eq!(TreeFormat::V1.leaf(file), TreeFormat::V1.empty_leaf());
+        assert_ne!(TreeFormat::V2.leaf(file), TreeFormat::V2.empty_leaf());
+        assert_ne!(TreeFormat::V2.leaf(&[]), TreeFormat::V2.empty_leaf());
+    }
+
+    #[test]
+    fn hash_leaves_in_parts() {
+        let file: Vec<u8> = (0..1000).map(|i| i as u8).collect();
+
+        for &format in &[TreeFormat::V1, TreeFormat::V2] {
+            let mut hasher = format.leaf_hasher();
+            for part in file.chunks(300) {
+                hasher.update(part);
+            }
+
+            assert_eq!(format.leaf(&file), hasher.finish());
+        }
+    }
+
+    #[test]
+    fn keep_the_hashes_of_chunks() {
+        let file: Vec<u8> = (0..3 * CHUNK_SIZE + 5).map(|i| i as u8).collect();
+
+        let mut hasher = TreeFormat::V3.leaf_hasher();
+        hasher.update(&file);
+        let (leaf, chunks) = hasher.finish_with_chunks();
+
+        assert_eq!(leaf, TreeFormat::V3.leaf(&file));
+        assert_eq!(chunks.len(), 4);
+        assert_eq!(ChunkTree::new(TreeFormat::V3, file.len() as u64, &chunks).leaf(), leaf);
+
+        let mut hasher = TreeFormat::V2.leaf_hasher();
+        hasher.update(&file);
+        assert_eq!(hasher.finish_with_chunks(), (TreeFormat::V2.leaf(&file), Vec::new()));
+    }
+}
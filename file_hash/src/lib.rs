@@ -9,6 +9,48 @@ use ring::{
 mod merkle_tree;
 pub use merkle_tree::*;
 
+mod format;
+pub use format::*;
+
+mod snapshot;
+pub use snapshot::*;
+
+mod proof;
+pub use proof::*;
+
+mod multi_proof;
+pub use multi_proof::*;
+
+mod consistency;
+pub use consistency::*;
+
+mod chunks;
+pub use chunks::*;
+
+mod encoding;
+pub use encoding::{
+    from_big_endian,
+    to_big_endian
+};
+
+
+/// Identifies a file and its location in a `MerkleTree`.
+///
+/// The width is selected with the `file_id_32` and `file_id_64` features,
+/// the widest enabled one wins. Defaults to 16 bits.
+#[cfg(feature = "file_id_64")]
+pub type FileID = u64;
+
+#[cfg(all(feature = "file_id_32", not(feature = "file_id_64")))]
+pub type FileID = u32;
+
+#[cfg(not(any(feature = "file_id_32", feature = "file_id_64")))]
+pub type FileID = u16;
+
+/// The number of bits in a `FileID`, and thereby the depth of a tree
+/// with room for every file.
+pub const FILE_ID_BITS: u8 = (std::mem::size_of::<FileID>() * 8) as u8;
+
 
 /// A 32-byte hash
 #[derive(Clone, Debug, Eq, PartialEq)]
@@ -64,6 +106,29 @@ impl Hash {
     pub fn as_bytes_mut(&mut self) -> &mut [u8] {
         &mut self.0
     }
+
+
+    /// Return the hash as a lowercase hexadecimal string
+    pub fn to_hex(&self) -> String {
+        self.0.iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect()
+    }
+
+    /// Parse a hash from a hexadecimal string
+    pub fn from_hex(hex: &str) -> Option<Hash> {
+        if hex.len() != 2 * Hash::BYTES || !hex.is_ascii() {
+            return None;
+        }
+
+        let mut hash = Hash::default();
+
+        for (i, byte) in hash.0.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&hex[2 * i..2 * i + 2], 16).ok()?;
+        }
+
+        Some(hash)
+    }
 }
 
 
@@ -2,20 +2,39 @@
 use std;
 use super::{
     Hash,
-    hash
+    MerkleProof,
+    MultiProof,
+    TreeFormat
 };
 
+use multi_proof::position;
+
 
 /// A perfectly binary hash tree
+///
+/// Only the paths leading to leaves are stored. Empty subtrees are
+/// represented by a single `Empty` node, whose hash is looked up in a
+/// table of precomputed hashes, one for each height in the tree.
 #[derive(Debug)]
 pub struct MerkleTree {
-    root: Node,
-    depth: u8
+    pub(crate) root: Node,
+    pub(crate) depth: u8,
+    pub(crate) hasher: Hasher
 }
 
 
+/// Hashes the branches of a tree, and knows the hash of every empty subtree
 #[derive(Debug)]
-enum Node {
+pub(crate) struct Hasher {
+    pub(crate) format: TreeFormat,
+
+    /// The hash of an empty subtree of a given height
+    pub(crate) empty: Vec<Hash>
+}
+
+
+#[derive(Debug)]
+pub(crate) enum Node {
     Branch {
         hash: Hash,
         left: Box<Node>,
@@ -28,38 +47,54 @@ enum Node {
 
     Empty
 }
-use self::Node::*;
+pub(crate) use self::Node::*;
 
 
 #[derive(Debug)]
 pub enum Error {
-    NodeNotPresent
+    NodeNotPresent,
+
+    /// Attempted to prove that an occupied slot is empty
+    NodePresent
 }
 
 pub type Result<T> = std::result::Result<T, Error>;
 
 
 impl MerkleTree {
-    pub fn new(depth: u8) -> MerkleTree {
-        if depth > 32 {
+    pub fn new(depth: u8, format: TreeFormat) -> MerkleTree {
+        if depth > 64 {
             panic!(format!("Attempted to construct too deep MerkelTree ({})", depth));
         }
 
         MerkleTree {
-            root: Node::empty_with_depth(depth),
-            depth
+            root: Empty,
+            depth,
+            hasher: Hasher::new(format, depth)
         }
     }
 
 
     /// Returns the root hash
     pub fn root(&self) -> Hash {
-        self.root.hash()
+        self.root.hash(self.depth, &self.hasher)
+    }
+
+
+    /// Returns the number of levels below the root
+    pub fn depth(&self) -> u8 {
+        self.depth
+    }
+
+
+    /// Returns the format used to hash the tree
+    pub fn format(&self) -> TreeFormat {
+        self.hasher.format
     }
 
 
     /// Returns a hash from the tree, if present
-    pub fn get(&self, node: usize) -> Result<Hash> {
+    pub fn get(&self, node: u64) -> Result<Hash> {
         if self.index_in_bounds(node) {
             self.root.get(node)
         } else {
@@ -69,89 +104,133 @@ impl MerkleTree {
 
 
     /// Inserts a new hash into the tree, returning the old hash, if present
-    pub fn insert(&mut self, node: usize, hash: Hash) -> Result<Option<Hash>> {
+    pub fn insert(&mut self, node: u64, hash: Hash) -> Result<Option<Hash>> {
         if self.index_in_bounds(node) {
-            self.root.insert(node, hash)
+            self.root.insert(node, hash, self.depth, &self.hasher)
         } else {
             Err(Error::NodeNotPresent)
         }
     }
 
 
-    /// Returns a sequence of hashes required to construct to root hash
-    /// from a leaf node.
-    ///
-    /// The sequence is built from the bottom up. That is, the first hash
-    /// in the sequence is the sibling of the leaf node. After combining
-    /// these hashes you will get the sibling of the next hash in the
-    /// sequence. This continues until the root node is reached.
-    pub fn dependencies(&self, node: usize) -> Result<Vec<Hash>> {
+    /// Removes a hash from the tree, leaving an empty slot. Returns the old
+    /// hash, if present
+    pub fn remove(&mut self, node: u64) -> Result<Option<Hash>> {
         if self.index_in_bounds(node) {
-            self.root.dependencies(node)
+            self.root.remove(node, self.depth, &self.hasher)
         } else {
             Err(Error::NodeNotPresent)
         }
     }
 
 
-    /// Reconstructs tho root hash based on all required sibling hashes and the
-    /// location of a file in a MerkleTree.
-    pub fn reconstruct_root_hash(dependencies: Vec<Hash>, mut node: usize, node_hash: Hash) ->
-    Hash {
-        let mask = 1 << (dependencies.len() - 1);
+    /// Returns a proof that a leaf is part of the tree
+    pub fn proof(&self, node: u64) -> Result<MerkleProof> {
+        if self.index_in_bounds(node) {
+            self.root.dependencies(node, self.depth, &self.hasher)
+                .map(|siblings| MerkleProof::new(self.format(), node, siblings))
+        } else {
+            Err(Error::NodeNotPresent)
+        }
+    }
+
+
+    /// Returns a proof that a slot in the tree is empty, verified against the
+    /// format's empty leaf.
+    pub fn absence_proof(&self, node: u64) -> Result<MerkleProof> {
+        match self.get(node) {
+            Ok(_) => Err(Error::NodePresent),
+            Err(_) => self.proof(node)
+        }
+    }
+
+
+    /// Returns a single proof that several leaves are part of the tree.
+    ///
+    /// The indices may be given in any order, and duplicates are ignored.
+    pub fn multi_proof(&self, nodes: &[u64]) -> Result<MultiProof> {
+        if nodes.is_empty() || !nodes.iter().all(|&node| self.index_in_bounds(node)) {
+            return Err(Error::NodeNotPresent);
+        }
+
+        let mut indices = nodes.to_vec();
+        indices.sort();
+        indices.dedup();
+
+        let mut known: Vec<u64> = indices.iter()
+            .map(|&index| position(index, self.depth))
+            .collect();
+
+        known.sort();
+
+        let mut siblings = Vec::new();
+
+        for level in (1..self.depth + 1).rev() {
+            let mut parents = Vec::with_capacity(known.len());
+            let mut i = 0;
 
-        let mut result = node_hash;
+            while i < known.len() {
+                let position = known[i];
 
-        for hash in dependencies {
-            // node index begins with 0 => node is on the left
-            // node index begins with 1 => node is on the right
-            let (left, right) = if node & mask == 0 {
-                (result, hash)
-            } else {
-                (hash, result)
-            };
+                if i + 1 < known.len() && known[i + 1] == position ^ 1 {
+                    i += 2;
+                } else {
+                    siblings.push(self.root.subtree_hash(position ^ 1, level, self.depth, &self.hasher));
+                    i += 1;
+                }
 
-            result = left.join(right);
-            node = node << 1;
+                parents.push(position >> 1);
+            }
+
+            known = parents;
         }
 
-        result
+        Ok(MultiProof::new(self.format(), self.depth, indices, siblings))
     }
 
 
-    fn index_in_bounds(&self, node: usize) -> bool {
-        node < 1usize << self.depth
+    fn index_in_bounds(&self, node: u64) -> bool {
+        // Shifting a u64 by 64 bits would overflow
+        self.depth == 64 || node >> self.depth == 0
     }
 }
 
 
-impl Node {
-    pub fn empty_with_depth(depth: u8) -> Node {
-        match depth {
-            0 => {
-                Empty
-            }
-
-            _ => {
-                let left = Box::new(Node::empty_with_depth(depth - 1));
-                let right = Box::new(Node::empty_with_depth(depth - 1));
+impl Hasher {
+    pub fn new(format: TreeFormat, depth: u8) -> Hasher {
+        let mut empty = vec![format.empty_leaf()];
 
-                Branch {
-                    hash: left.hash().join(right.hash()),
+        for height in 0..depth as usize {
+            let below = format.branch(&empty[height], &empty[height]);
+            empty.push(below);
+        }
 
-                    left,
-                    right,
-                }
-            }
+        Hasher {
+            format,
+            empty
         }
     }
 
 
+    /// Returns the hash of a branch from the hashes of its children
+    pub fn join(&self, left: Hash, right: Hash) -> Hash {
+        self.format.branch(&left, &right)
+    }
+
+
+    /// Returns the hash of an empty subtree of a given height
+    pub fn empty(&self, height: u8) -> Hash {
+        self.empty[height as usize].clone()
+    }
+}
+
+
+impl Node {
     /// Searches the tree for a node and returns it's hash
-    pub fn get(&self, node: usize) -> Result<Hash> {
+    pub fn get(&self, node: u64) -> Result<Hash> {
         match self {
-            &Leaf { .. } if node == 0 => {
-                Ok(self.hash())
+            &Leaf { ref hash } if node == 0 => {
+                Ok(hash.clone())
             },
 
             &Branch { ref left, ref right, .. } => {
@@ -171,13 +250,24 @@ impl Node {
 
 
     /// Inserts a new hash into the tree, updating all dependencies
-    pub fn insert(&mut self, node: usize, hash: Hash) -> Result<Option<Hash>> {
+    pub fn insert(&mut self, node: u64, hash: Hash, height: u8, hasher: &Hasher) -> Result<Option<Hash>> {
         match self {
-            &mut Empty if node == 0 => {
+            &mut Empty if height == 0 && node == 0 => {
                 *self = Leaf { hash };
                 Ok(None)
             }
 
+            &mut Empty if height > 0 => {
+                // Expand the empty subtree one level down and continue from there
+                *self = Branch {
+                    hash: hasher.empty(height),
+                    left: Box::new(Empty),
+                    right: Box::new(Empty)
+                };
+
+                self.insert(node, hash, height, hasher)
+            }
+
             &mut Leaf { hash: ref mut current } if node == 0 => {
                 use std::mem::replace;
                 Ok(Some(replace(current, hash)))
@@ -188,13 +278,13 @@ impl Node {
 
                 // Update children hashes
                 let old = if node & 1 == 1 {
-                    right.insert(next_node, hash)
+                    right.insert(next_node, hash, height - 1, hasher)
                 } else {
-                    left.insert(next_node, hash)
+                    left.insert(next_node, hash, height - 1, hasher)
                 };
 
                 // Update hash
-                *current = left.hash().join(right.hash());
+                *current = hasher.join(left.hash(height - 1, hasher), right.hash(height - 1, hasher));
 
                 old
             }
@@ -205,10 +295,64 @@ impl Node {
     }
 
 
-    /// Returns the hash of a node
-    pub fn hash(&self) -> Hash {
+    /// Removes a hash from the tree, collapsing branches which no longer
+    /// lead to any leaves
+    pub fn remove(&mut self, node: u64, height: u8, hasher: &Hasher) -> Result<Option<Hash>> {
         match self {
-            &Empty => hash(b"Hello, world!"),
+            &mut Empty => {
+                Ok(None)
+            }
+
+            &mut Leaf { .. } if node == 0 => {
+                let old = self.hash(height, hasher);
+                *self = Empty;
+                Ok(Some(old))
+            }
+
+            &mut Branch { .. } => {
+                let next_node = node >> 1;
+
+                let (old, collapse) = match self {
+                    &mut Branch { ref mut left, ref mut right, hash: ref mut current } => {
+                        let old = if node & 1 == 1 {
+                            right.remove(next_node, height - 1, hasher)
+                        } else {
+                            left.remove(next_node, height - 1, hasher)
+                        };
+
+                        *current = hasher.join(left.hash(height - 1, hasher), right.hash(height - 1, hasher));
+
+                        (old, left.is_empty() && right.is_empty())
+                    }
+
+                    _ => unreachable!()
+                };
+
+                if collapse {
+                    *self = Empty;
+                }
+
+                old
+            }
+
+            // Attempted to search deeper into leaf
+            _ => Err(Error::NodeNotPresent)
+        }
+    }
+
+
+    fn is_empty(&self) -> bool {
+        match self {
+            &Empty => true,
+            _ => false
+        }
+    }
+
+
+    /// Returns the hash of a node at a specific height
+    pub fn hash(&self, height: u8, hasher: &Hasher) -> Hash {
+        match self {
+            &Empty => hasher.empty(height),
             &Leaf { ref hash, .. } => {
                 hash.clone()
             }
@@ -219,11 +363,44 @@ impl Node {
     }
 
 
+    /// Returns the hash of a node some levels below this one, found by its
+    /// position among all nodes on that level.
+    pub fn subtree_hash(&self, position: u64, levels: u8, height: u8, hasher: &Hasher) -> Hash {
+        if levels == 0 {
+            return self.hash(height, hasher);
+        }
+
+        match self {
+            &Branch { ref left, ref right, .. } => {
+                // The highest bit of the position is the direction from this node
+                if position >> (levels - 1) & 1 == 1 {
+                    right.subtree_hash(position, levels - 1, height - 1, hasher)
+                } else {
+                    left.subtree_hash(position, levels - 1, height - 1, hasher)
+                }
+            }
+
+            // Everything below an empty node is empty
+            _ => hasher.empty(height - levels)
+        }
+    }
+
+
     /// Returns all hashes required to construct a node's
     /// hash from one of it's children.
-    pub fn dependencies(&self, node: usize) -> Result<Vec<Hash>> {
+    ///
+    /// The sequence is built from the bottom up. That is, the first hash
+    /// in the sequence is the sibling of the leaf node. After combining
+    /// these hashes you will get the sibling of the next hash in the
+    /// sequence. This continues until the root node is reached.
+    pub fn dependencies(&self, node: u64, height: u8, hasher: &Hasher) -> Result<Vec<Hash>> {
         match self {
-            &Empty | &Leaf { .. } if node == 0 => {
+            // Every sibling below an empty node is empty as well
+            &Empty => {
+                Ok(hasher.empty[..height as usize].to_vec())
+            },
+
+            &Leaf { .. } if node == 0 => {
                 Ok(Vec::new())
             },
 
@@ -231,15 +408,15 @@ impl Node {
                 let next_node = node >> 1;
 
                 if node & 1 == 1 {
-                    right.dependencies(next_node)
+                    right.dependencies(next_node, height - 1, hasher)
                         .map(|mut hashes| {
-                            hashes.push(left.hash());
+                            hashes.push(left.hash(height - 1, hasher));
                             hashes
                         })
                 } else {
-                    left.dependencies(next_node)
+                    left.dependencies(next_node, height - 1, hasher)
                         .map(|mut hashes| {
-                            hashes.push(right.hash());
+                            hashes.push(right.hash(height - 1, hasher));
                             hashes
                         })
                 }
@@ -255,10 +432,11 @@ impl Node {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use hash;
 
     #[test]
     fn update_tree() {
-        let mut tree = MerkleTree::new(1);
+        let mut tree = MerkleTree::new(1, TreeFormat::V1);
         println!("tree: {:x?}", tree);
         println!("root: {:x?}", tree.root());
 
@@ -268,15 +446,108 @@ mod tests {
         println!("tree: {:x?}", tree);
         println!("root: {:x?}", tree.root());
 
-        assert_eq!(hash.join(Empty.hash()), tree.root());
+        assert_eq!(hash.join(tree.hasher.empty(0)), tree.root());
     }
 
 
     #[test]
     fn dependencies() {
-        let mut tree = MerkleTree::new(5);
+        let mut tree = MerkleTree::new(5, TreeFormat::default());
         println!("tree: {:#x?}", tree);
-        println!("dependencies: {:#?}", tree.dependencies(18).unwrap().len());
+        println!("dependencies: {:#?}", tree.proof(18).unwrap().siblings().len());
     }
-}
 
+
+    #[test]
+    fn sparse_matches_full_tree() {
+        for &format in &[TreeFormat::V1, TreeFormat::V2] {
+            let depth = 4;
+            let mut tree = MerkleTree::new(depth, format);
+
+            let mut leaves = vec![format.empty_leaf(); 1 << depth];
+            for &node in &[3, 9, 12, 3] {
+                let leaf = format.leaf(&[node as u8]);
+                tree.insert(node, leaf.clone()).unwrap();
+                leaves[node as usize] = leaf;
+            }
+
+            // Build each level of the full tree from the bottom up. The least
+            // significant bit of a node decides its direction from the root, so
+            // siblings are half a level apart.
+            let mut level = leaves;
+            while level.len() > 1 {
+                let half = level.len() / 2;
+                level = (0..half)
+                    .map(|i| format.branch(&level[i], &level[i + half]))
+                    .collect();
+            }
+
+            assert_eq!(level[0], tree.root());
+
+            for node in 0..1u64 << depth {
+                let proof = tree.proof(node).unwrap();
+                assert_eq!(depth as usize, proof.siblings().len());
+
+                let leaf = tree.get(node).unwrap_or(format.empty_leaf());
+                assert_eq!(Ok(()), proof.verify(&leaf, &tree.root()));
+            }
+        }
+    }
+
+
+    #[test]
+    fn full_width_tree() {
+        let mut tree = MerkleTree::new(64, TreeFormat::default());
+        let empty_root = tree.root();
+
+        let node = u64::max_value();
+        let leaf = hash(&[1, 2, 3]);
+        tree.insert(node, leaf.clone()).unwrap();
+
+        assert_ne!(empty_root, tree.root());
+        assert_eq!(leaf, tree.get(node).unwrap());
+        assert!(tree.get(node >> 1).is_err());
+
+        let proof = tree.proof(node).unwrap();
+        assert_eq!(64, proof.siblings().len());
+        assert_eq!(Ok(()), proof.verify(&leaf, &tree.root()));
+    }
+
+
+    #[test]
+    fn remove_restores_empty_tree() {
+        let mut tree = MerkleTree::new(8, TreeFormat::default());
+        let empty_root = tree.root();
+
+        tree.insert(5, hash(b"five")).unwrap();
+        tree.insert(133, hash(b"one hundred and thirty three")).unwrap();
+        let root = tree.root();
+
+        assert_eq!(Some(hash(b"five")), tree.remove(5).unwrap());
+        assert_eq!(None, tree.remove(5).unwrap());
+        assert!(tree.get(5).is_err());
+
+        tree.insert(5, hash(b"five")).unwrap();
+        assert_eq!(root, tree.root());
+
+        tree.remove(5).unwrap();
+        tree.remove(133).unwrap();
+        assert_eq!(empty_root, tree.root());
+        assert!(tree.root.is_empty());
+    }
+
+
+    #[test]
+    fn absence_proof() {
+        let format = TreeFormat::V2;
+        let mut tree = MerkleTree::new(16, format);
+        tree.insert(1342, format.leaf(b"Super secret message")).unwrap();
+
+        assert!(tree.absence_proof(1342).is_err());
+
+        for &node in &[0, 1343, 65535] {
+            let proof = tree.absence_proof(node).unwrap();
+            assert_eq!(Ok(()), proof.verify(&format.empty_leaf(), &tree.root()));
+        }
+    }
+}
@@ -0,0 +1,290 @@
+use std;
+
+use super::{
+    Hash,
+    ProofError,
+    TreeFormat
+};
+
+use encoding::{
+    Reader,
+    to_big_endian
+};
+
+
+/// The hashes required to reconstruct the root hash of a `MerkleTree` from
+/// several of its leaves at once.
+///
+/// A sibling is only included if it can't be computed from the leaves, so
+/// leaves close to each other share most of their siblings.
+///
+/// Encoded as the version of the tree format, the depth of the tree and the
+/// number of leaves, followed by the leaf indices and then the siblings. The
+/// number of leaves and the indices are big-endian 64-bit integers.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct MultiProof {
+    format: TreeFormat,
+    depth: u8,
+
+    /// Sorted in ascending order, without duplicates
+    indices: Vec<u64>,
+
+    /// Sorted from the bottom up. Within a level, siblings are sorted by the
+    /// directions leading to them, taken from the root.
+    siblings: Vec<Hash>
+}
+
+
+type Result<T> = std::result::Result<T, ProofError>;
+
+
+impl MultiProof {
+    pub(crate) fn new(format: TreeFormat, depth: u8, indices: Vec<u64>, siblings: Vec<Hash>) -> MultiProof {
+        MultiProof {
+            format,
+            depth,
+            indices,
+            siblings
+        }
+    }
+
+
+    pub fn format(&self) -> TreeFormat {
+        self.format
+    }
+
+    pub fn depth(&self) -> u8 {
+        self.depth
+    }
+
+    /// The indices of the leaves, in the order their hashes are expected
+    pub fn indices(&self) -> &[u64] {
+        &self.indices
+    }
+
+    pub fn siblings(&self) -> &[Hash] {
+        &self.siblings
+    }
+
+
+    /// Reconstruct the root hash of the tree from the hashes of the leaves,
+    /// given in the same order as `indices`
+    pub fn root(&self, leaves: &[Hash]) -> Result<Hash> {
+        if self.depth > 64 {
+            return Err(ProofError::InvalidDepth);
+        }
+
+        if leaves.len() != self.indices.len() {
+            return Err(ProofError::LeafCountMismatch);
+        }
+
+        if self.indices.is_empty() || self.indices.windows(2).any(|pair| pair[0] >= pair[1]) {
+            return Err(ProofError::InvalidIndices);
+        }
+
+        if self.depth < 64 && self.indices.iter().any(|index| index >> self.depth != 0) {
+            return Err(ProofError::IndexOutOfBounds);
+        }
+
+        let mut known: Vec<(u64, Hash)> = self.indices.iter()
+            .map(|&index| position(index, self.depth))
+            .zip(leaves.iter().cloned())
+            .collect();
+
+        known.sort_by_key(|&(position, _)| position);
+
+        let mut siblings = self.siblings.iter();
+
+        for _ in 0..self.depth {
+            let mut parents = Vec::with_capacity(known.len());
+            let mut i = 0;
+
+            while i < known.len() {
+                let (position, ref hash) = known[i];
+
+                // The lowest bit of a position tells if the node is a right child
+                let sibling_known = i + 1 < known.len() && known[i + 1].0 == position ^ 1;
+
+                let parent = if sibling_known {
+                    i += 2;
+                    self.format.branch(hash, &known[i - 1].1)
+                } else {
+                    i += 1;
+                    let sibling = siblings.next().ok_or(ProofError::InvalidLength)?;
+
+                    if position & 1 == 0 {
+                        self.format.branch(hash, sibling)
+                    } else {
+                        self.format.branch(sibling, hash)
+                    }
+                };
+
+                parents.push((position >> 1, parent));
+            }
+
+            known = parents;
+        }
+
+        if siblings.next().is_some() {
+            return Err(ProofError::InvalidLength);
+        }
+
+        Ok(known.remove(0).1)
+    }
+
+
+    /// Verify that all leaves are part of the tree with the root hash
+    pub fn verify(&self, leaves: &[Hash], root: &Hash) -> Result<()> {
+        if self.root(leaves)? == *root {
+            Ok(())
+        } else {
+            Err(ProofError::RootMismatch)
+        }
+    }
+
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(
+            10 + self.indices.len() * 8 + self.siblings.len() * Hash::BYTES
+        );
+
+        bytes.push(self.format.version());
+        bytes.push(self.depth);
+
+        bytes.extend_from_slice(&to_big_endian(self.indices.len() as u64, 8));
+        for &index in &self.indices {
+            bytes.extend_from_slice(&to_big_endian(index, 8));
+        }
+
+        for sibling in &self.siblings {
+            bytes.extend_from_slice(sibling.as_bytes());
+        }
+
+        bytes
+    }
+
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<MultiProof> {
+        let mut reader = Reader(bytes);
+
+        let version = reader.byte().ok_or(ProofError::InvalidLength)?;
+        let format = TreeFormat::from_version(version)
+            .ok_or(ProofError::UnknownFormat(version))?;
+
+        let depth = reader.byte().ok_or(ProofError::InvalidLength)?;
+
+        let count = reader.u64().ok_or(ProofError::InvalidLength)?;
+        if count > reader.0.len() as u64 / 8 {
+            return Err(ProofError::InvalidLength);
+        }
+
+        let mut indices = Vec::with_capacity(count as usize);
+        for _ in 0..count {
+            indices.push(reader.u64().ok_or(ProofError::InvalidLength)?);
+        }
+
+        if reader.0.len() % Hash::BYTES != 0 {
+            return Err(ProofError::InvalidLength);
+        }
+
+        let mut siblings = Vec::with_capacity(reader.0.len() / Hash::BYTES);
+        while let Some(sibling) = reader.hash() {
+            siblings.push(sibling);
+        }
+
+        Ok(MultiProof {
+            format,
+            depth,
+            indices,
+            siblings
+        })
+    }
+}
+
+
+/// Returns the position of a leaf among all leaves, counted from the left.
+///
+/// The directions leading to a leaf are stored in an index from the least
+/// significant bit, and in a position from the most significant. Siblings
+/// therefore only differ in the lowest bit of their positions, and the
+/// position of a parent is the position of a child shifted one bit right.
+pub(crate) fn position(index: u64, depth: u8) -> u64 {
+    let mut position = 0;
+
+    for level in 0..depth {
+        position = position << 1 | (index >> level & 1);
+    }
+
+    position
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use MerkleTree;
+
+    fn example_tree() -> MerkleTree {
+        let format = TreeFormat::default();
+        let mut tree = MerkleTree::new(8, format);
+
+        for node in (0..256).filter(|node| node % 3 == 0) {
+            tree.insert(node, format.leaf(&[node as u8])).unwrap();
+        }
+
+        tree
+    }
+
+    #[test]
+    fn verify_and_encode() {
+        let tree = example_tree();
+
+        for indices in &[vec![0], vec![3, 7, 8, 200], vec![255, 0, 128, 1, 129, 0], (0..256).collect()] {
+            let proof = tree.multi_proof(indices).unwrap();
+
+            let leaves: Vec<Hash> = proof.indices().iter()
+                .map(|&index| tree.get(index).unwrap_or(tree.format().empty_leaf()))
+                .collect();
+
+            assert_eq!(Ok(()), proof.verify(&leaves, &tree.root()));
+            assert_eq!(Ok(proof.clone()), MultiProof::from_bytes(&proof.to_bytes()));
+
+            let mut tampered = leaves.clone();
+            tampered[0] = tree.format().leaf(b"tampered");
+            assert_eq!(Err(ProofError::RootMismatch), proof.verify(&tampered, &tree.root()));
+        }
+    }
+
+    #[test]
+    fn shared_siblings_are_included_once() {
+        let tree = example_tree();
+
+        // Siblings of each other, so only the 7 hashes above them are needed
+        let proof = tree.multi_proof(&[5, 133]).unwrap();
+        assert_eq!(7, proof.siblings().len());
+
+        // Every leaf known, nothing else needed
+        let proof = tree.multi_proof(&(0..256).collect::<Vec<_>>()).unwrap();
+        assert_eq!(0, proof.siblings().len());
+    }
+
+    #[test]
+    fn reject_malformed_proofs() {
+        let tree = example_tree();
+        let proof = tree.multi_proof(&[3, 7]).unwrap();
+        let leaves = [tree.get(3).unwrap(), tree.format().empty_leaf()];
+
+        assert_eq!(Err(ProofError::LeafCountMismatch), proof.verify(&leaves[..1], &tree.root()));
+
+        let bytes = proof.to_bytes();
+        for length in 0..bytes.len() {
+            match MultiProof::from_bytes(&bytes[..length]) {
+                Ok(truncated) => assert!(truncated.verify(&leaves, &tree.root()).is_err()),
+                Err(_) => {}
+            }
+        }
+
+        assert!(tree.multi_proof(&[]).is_err());
+        assert!(tree.multi_proof(&[256]).is_err());
+    }
+}
@@ -0,0 +1,213 @@
+use std;
+
+use super::{
+    Hash,
+    TreeFormat
+};
+
+use encoding::{
+    Reader,
+    to_big_endian
+};
+
+
+/// The hashes required to reconstruct the root hash of a `MerkleTree` from
+/// one of its leaves.
+///
+/// Encoded as the version of the tree format, the depth of the tree and the
+/// index of the leaf as a big-endian 64-bit integer, followed by the siblings.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct MerkleProof {
+    format: TreeFormat,
+    index: u64,
+    depth: u8,
+
+    /// Sorted from the bottom up. That is, the first hash is the sibling of
+    /// the leaf, and the last one the sibling of the root's child.
+    siblings: Vec<Hash>
+}
+
+
+#[derive(Debug, Eq, PartialEq)]
+pub enum ProofError {
+    /// The root hash reconstructed from the leaf is not the expected one
+    RootMismatch,
+
+    /// The number of siblings does not match the depth of the tree
+    InvalidDepth,
+
+    /// The leaf index does not fit in a tree of the proof's depth
+    IndexOutOfBounds,
+
+    /// The leaf indices of a multi-proof are missing, duplicated or not sorted
+    InvalidIndices,
+
+    /// A multi-proof was given another number of leaves than it has indices
+    LeafCountMismatch,
+
+    /// An encoded proof is too short or too long
+    InvalidLength,
+
+    /// An encoded proof uses a tree format this version does not understand
+    UnknownFormat(u8),
+
+    /// A proof of chunks uses a tree format which does not split files into chunks
+    ChunksUnsupported,
+
+    /// A chunk is not as long as its place in the file requires
+    InvalidChunk
+}
+
+type Result<T> = std::result::Result<T, ProofError>;
+
+
+impl MerkleProof {
+    pub fn new(format: TreeFormat, index: u64, siblings: Vec<Hash>) -> MerkleProof {
+        MerkleProof {
+            format,
+            index,
+            depth: siblings.len() as u8,
+            siblings
+        }
+    }
+
+
+    pub fn format(&self) -> TreeFormat {
+        self.format
+    }
+
+    pub fn index(&self) -> u64 {
+        self.index
+    }
+
+    pub fn depth(&self) -> u8 {
+        self.depth
+    }
+
+    pub fn siblings(&self) -> &[Hash] {
+        &self.siblings
+    }
+
+
+    /// Reconstruct the root hash of the tree from the hash of the leaf
+    pub fn root(&self, leaf: &Hash) -> Result<Hash> {
+        if self.siblings.len() != self.depth as usize || self.depth > 64 {
+            return Err(ProofError::InvalidDepth);
+        }
+
+        if self.depth < 64 && self.index >> self.depth != 0 {
+            return Err(ProofError::IndexOutOfBounds);
+        }
+
+        let mut result = leaf.clone();
+
+        for (level, sibling) in self.siblings.iter().enumerate() {
+            // The deepest direction is stored in the most significant bit
+            // of the index, the direction from the root in the least.
+            let direction = self.index >> (self.depth as usize - 1 - level) & 1;
+
+            result = if direction == 0 {
+                self.format.branch(&result, sibling)
+            } else {
+                self.format.branch(sibling, &result)
+            };
+        }
+
+        Ok(result)
+    }
+
+
+    /// Verify that the leaf is part of the tree with the root hash
+    pub fn verify(&self, leaf: &Hash, root: &Hash) -> Result<()> {
+        if self.root(leaf)? == *root {
+            Ok(())
+        } else {
+            Err(ProofError::RootMismatch)
+        }
+    }
+
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(10 + self.siblings.len() * Hash::BYTES);
+
+        bytes.push(self.format.version());
+        bytes.push(self.depth);
+        bytes.extend_from_slice(&to_big_endian(self.index, 8));
+
+        for sibling in &self.siblings {
+            bytes.extend_from_slice(sibling.as_bytes());
+        }
+
+        bytes
+    }
+
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<MerkleProof> {
+        let mut reader = Reader(bytes);
+        let proof = MerkleProof::read(&mut reader)?;
+
+        if !reader.0.is_empty() {
+            return Err(ProofError::InvalidLength);
+        }
+
+        Ok(proof)
+    }
+
+
+    /// Read a proof from the front of some bytes
+    pub(crate) fn read(reader: &mut Reader) -> Result<MerkleProof> {
+        let version = reader.byte().ok_or(ProofError::InvalidLength)?;
+        let format = TreeFormat::from_version(version)
+            .ok_or(ProofError::UnknownFormat(version))?;
+
+        let depth = reader.byte().ok_or(ProofError::InvalidLength)?;
+        let index = reader.u64().ok_or(ProofError::InvalidLength)?;
+
+        let mut siblings = Vec::with_capacity(depth as usize);
+        for _ in 0..depth {
+            siblings.push(reader.hash().ok_or(ProofError::InvalidLength)?);
+        }
+
+        Ok(MerkleProof {
+            format,
+            index,
+            depth,
+            siblings
+        })
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use MerkleTree;
+
+    #[test]
+    fn verify_and_encode() {
+        let format = TreeFormat::default();
+        let mut tree = MerkleTree::new(16, format);
+
+        let leaf = format.leaf(b"Super secret message");
+        tree.insert(1342, leaf.clone()).unwrap();
+
+        let proof = tree.proof(1342).unwrap();
+        assert_eq!(Ok(()), proof.verify(&leaf, &tree.root()));
+        assert_eq!(Err(ProofError::RootMismatch), proof.verify(&format.empty_leaf(), &tree.root()));
+
+        let bytes = proof.to_bytes();
+        assert_eq!(Ok(proof), MerkleProof::from_bytes(&bytes));
+
+        assert_eq!(Err(ProofError::InvalidLength), MerkleProof::from_bytes(&bytes[..bytes.len() - 1]));
+        assert_eq!(Err(ProofError::InvalidLength), MerkleProof::from_bytes(&[]));
+    }
+
+    #[test]
+    fn empty_proof() {
+        let leaf = TreeFormat::default().leaf(b"alone");
+        let proof = MerkleProof::new(TreeFormat::default(), 0, Vec::new());
+
+        assert_eq!(Ok(leaf.clone()), proof.root(&leaf));
+        assert_eq!(Err(ProofError::IndexOutOfBounds), MerkleProof::new(TreeFormat::default(), 1, Vec::new()).root(&leaf));
+    }
+}
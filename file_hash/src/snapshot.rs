@@ -0,0 +1,342 @@
+//! A compact binary format for storing a `MerkleTree`.
+//!
+//! All integers are big-endian:
+//!
+//! | Bytes | Content |
+//! | --- | --- |
+//! | 4 | Magic number, `MRKL` |
+//! | 1 | Format version |
+//! | 1 | Depth of the tree |
+//! | 1 | Version of the `TreeFormat` (not present in version 1, which is always `V1`) |
+//! | 1 | Flags, bit 0 is set if branch hashes are included |
+//! | 8 | Number of leaves |
+//! | 40 each | Index and hash of every leaf, in pre-order |
+//! | 8 + 32 each | Number of branches and the hash of every branch, in pre-order (only if flagged) |
+//! | 32 | Root hash |
+//! | 32 | SHA256 checksum of everything above |
+//!
+//! Including the branch hashes makes the snapshot larger, but allows the tree
+//! to be loaded without computing any hashes.
+
+use std::{
+    self,
+    io::{
+        self,
+        Read,
+        Write
+    },
+    iter::Peekable,
+    slice
+};
+
+use super::{
+    Hash,
+    MerkleTree,
+    TreeFormat,
+    hash
+};
+
+use encoding::{
+    Reader,
+    to_big_endian
+};
+
+use merkle_tree::{
+    Node,
+    Hasher,
+    Empty,
+    Leaf,
+    Branch
+};
+
+
+const MAGIC: &'static [u8; 4] = b"MRKL";
+const VERSION: u8 = 2;
+
+const FLAG_BRANCHES: u8 = 1;
+
+
+#[derive(Debug)]
+pub enum SnapshotError {
+    Io(io::Error),
+
+    /// The data does not start with the magic number of a snapshot
+    InvalidMagic,
+
+    /// The snapshot was written in a format this version does not understand
+    UnsupportedVersion(u8),
+
+    /// The snapshot ended before all of its contents were read
+    Truncated,
+
+    /// The snapshot's checksum does not match its contents
+    ChecksumMismatch,
+
+    /// The contents do not describe a valid tree
+    Corrupt
+}
+
+type Result<T> = std::result::Result<T, SnapshotError>;
+
+
+impl MerkleTree {
+    /// Write a snapshot of the tree, optionally including the hashes of all
+    /// branches for faster loading.
+    pub fn save<W: Write>(&self, mut writer: W, include_branches: bool) -> io::Result<()> {
+        let mut leaves = Vec::new();
+        let mut branches = Vec::new();
+        self.root.collect(0, 0, &mut leaves, &mut branches);
+
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(MAGIC);
+        bytes.push(VERSION);
+        bytes.push(self.depth);
+        bytes.push(self.format().version());
+        bytes.push(if include_branches { FLAG_BRANCHES } else { 0 });
+
+        bytes.extend_from_slice(&to_big_endian(leaves.len() as u64, 8));
+        for (index, hash) in leaves {
+            bytes.extend_from_slice(&to_big_endian(index, 8));
+            bytes.extend_from_slice(hash.as_bytes());
+        }
+
+        if include_branches {
+            bytes.extend_from_slice(&to_big_endian(branches.len() as u64, 8));
+            for hash in branches {
+                bytes.extend_from_slice(hash.as_bytes());
+            }
+        }
+
+        bytes.extend_from_slice(self.root().as_bytes());
+
+        let checksum = hash(&bytes);
+        bytes.extend_from_slice(checksum.as_bytes());
+
+        writer.write_all(&bytes)
+    }
+
+
+    /// Read a tree from a snapshot
+    pub fn load<R: Read>(mut reader: R) -> Result<MerkleTree> {
+        let mut bytes = Vec::new();
+        reader.read_to_end(&mut bytes).map_err(SnapshotError::Io)?;
+
+        let mut snapshot = Reader(&bytes);
+
+        if snapshot.take(MAGIC.len()).ok_or(SnapshotError::Truncated)? != MAGIC {
+            return Err(SnapshotError::InvalidMagic);
+        }
+
+        let version = match snapshot.byte().ok_or(SnapshotError::Truncated)? {
+            version if version >= 1 && version <= VERSION => version,
+            version => return Err(SnapshotError::UnsupportedVersion(version))
+        };
+
+        if bytes.len() < Hash::BYTES {
+            return Err(SnapshotError::Truncated);
+        }
+
+        let (contents, checksum) = bytes.split_at(bytes.len() - Hash::BYTES);
+        if hash(contents).as_bytes() != checksum {
+            return Err(SnapshotError::ChecksumMismatch);
+        }
+
+        let depth = snapshot.byte().ok_or(SnapshotError::Truncated)?;
+
+        let format = if version == 1 {
+            TreeFormat::V1
+        } else {
+            let format = snapshot.byte().ok_or(SnapshotError::Truncated)?;
+            TreeFormat::from_version(format).ok_or(SnapshotError::Corrupt)?
+        };
+
+        let flags = snapshot.byte().ok_or(SnapshotError::Truncated)?;
+
+        if depth > 64 {
+            return Err(SnapshotError::Corrupt);
+        }
+
+        let leaf_count = snapshot.u64().ok_or(SnapshotError::Truncated)?;
+        let mut leaves = Vec::new();
+        for _ in 0..leaf_count {
+            let index = snapshot.u64().ok_or(SnapshotError::Truncated)?;
+            let hash = snapshot.hash().ok_or(SnapshotError::Truncated)?;
+
+            if depth < 64 && index >> depth != 0 {
+                return Err(SnapshotError::Corrupt);
+            }
+
+            leaves.push((index, hash));
+        }
+
+        let mut branches = Vec::new();
+        if flags & FLAG_BRANCHES != 0 {
+            let branch_count = snapshot.u64().ok_or(SnapshotError::Truncated)?;
+            for _ in 0..branch_count {
+                branches.push(snapshot.hash().ok_or(SnapshotError::Truncated)?);
+            }
+        }
+
+        let root_hash = snapshot.hash().ok_or(SnapshotError::Truncated)?;
+
+        if snapshot.0.len() != Hash::BYTES {
+            return Err(SnapshotError::Corrupt);
+        }
+
+        let hasher = Hasher::new(format, depth);
+
+        let root = {
+            let mut leaves = leaves.iter().peekable();
+            let mut branches = if flags & FLAG_BRANCHES != 0 {
+                Some(branches.iter())
+            } else {
+                None
+            };
+
+            let root = Node::build(0, 0, depth, &hasher, &mut leaves, &mut branches);
+
+            // Every leaf and branch has to end up in the tree
+            let unused_branches = branches.map_or(false, |mut branches| branches.next().is_some());
+            if leaves.next().is_some() || unused_branches {
+                return Err(SnapshotError::Corrupt);
+            }
+
+            root?
+        };
+
+        let tree = MerkleTree {
+            root,
+            depth,
+            hasher
+        };
+
+        if tree.root() != root_hash {
+            return Err(SnapshotError::Corrupt);
+        }
+
+        Ok(tree)
+    }
+}
+
+
+impl Node {
+    /// Collect every leaf, with its index, and the hash of every branch in pre-order.
+    ///
+    /// `path` holds the directions taken to reach this node, the first
+    /// direction in the least significant bit.
+    fn collect(&self, path: u64, level: u8, leaves: &mut Vec<(u64, Hash)>, branches: &mut Vec<Hash>) {
+        match self {
+            &Empty => {},
+
+            &Leaf { ref hash } => {
+                leaves.push((path, hash.clone()));
+            }
+
+            &Branch { ref hash, ref left, ref right } => {
+                branches.push(hash.clone());
+
+                left.collect(path, level + 1, leaves, branches);
+                right.collect(path | 1 << level, level + 1, leaves, branches);
+            }
+        }
+    }
+
+
+    /// Build a node from leaves in pre-order, using branch hashes if present
+    fn build(path: u64, level: u8, depth: u8, hasher: &Hasher,
+             leaves: &mut Peekable<slice::Iter<(u64, Hash)>>,
+             branches: &mut Option<slice::Iter<Hash>>) -> Result<Node> {
+        let in_subtree = match leaves.peek() {
+            // Compare the directions taken so far to reach this node
+            Some(&&(index, _)) => level == 64 || index & ((1 << level) - 1) == path,
+            None => false
+        };
+
+        if !in_subtree {
+            return Ok(Empty);
+        }
+
+        if level == depth {
+            let &(_, ref hash) = leaves.next().unwrap();
+            return Ok(Leaf { hash: hash.clone() });
+        }
+
+        let cached = match branches.as_mut() {
+            Some(branches) => Some(branches.next().ok_or(SnapshotError::Corrupt)?.clone()),
+            None => None
+        };
+
+        let left = Node::build(path, level + 1, depth, hasher, leaves, branches)?;
+        let right = Node::build(path | 1 << level, level + 1, depth, hasher, leaves, branches)?;
+
+        let height = depth - level - 1;
+        let hash = match cached {
+            Some(hash) => hash,
+            None => hasher.join(left.hash(height, hasher), right.hash(height, hasher))
+        };
+
+        Ok(Branch {
+            hash,
+            left: Box::new(left),
+            right: Box::new(right)
+        })
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn example_tree() -> MerkleTree {
+        let mut tree = MerkleTree::new(16, TreeFormat::default());
+
+        for &node in &[0, 1, 2, 1342, 40000, 65535] {
+            let leaf = tree.format().leaf(&[node as u8, (node >> 8) as u8]);
+            tree.insert(node, leaf).unwrap();
+        }
+
+        tree
+    }
+
+    #[test]
+    fn save_and_load() {
+        let tree = example_tree();
+
+        for &include_branches in &[false, true] {
+            let mut bytes = Vec::new();
+            tree.save(&mut bytes, include_branches).unwrap();
+
+            let loaded = MerkleTree::load(&bytes[..]).unwrap();
+            assert_eq!(tree.root(), loaded.root());
+            assert_eq!(tree.get(1342).unwrap(), loaded.get(1342).unwrap());
+            assert_eq!(tree.proof(40000).unwrap(), loaded.proof(40000).unwrap());
+        }
+    }
+
+    #[test]
+    fn empty_tree() {
+        let tree = MerkleTree::new(64, TreeFormat::default());
+
+        let mut bytes = Vec::new();
+        tree.save(&mut bytes, true).unwrap();
+
+        assert_eq!(tree.root(), MerkleTree::load(&bytes[..]).unwrap().root());
+    }
+
+    #[test]
+    fn reject_damaged_snapshots() {
+        let mut bytes = Vec::new();
+        example_tree().save(&mut bytes, true).unwrap();
+
+        for length in 0..bytes.len() {
+            assert!(MerkleTree::load(&bytes[..length]).is_err());
+        }
+
+        for i in 0..bytes.len() {
+            let mut damaged = bytes.clone();
+            damaged[i] ^= 0x10;
+            assert!(MerkleTree::load(&damaged[..]).is_err());
+        }
+    }
+}
@@ -0,0 +1,299 @@
+use std::fmt;
+
+use super::{
+    TreeFormat,
+    FILE_ID_BITS,
+    VERSION,
+    from_big_endian,
+    to_big_endian
+};
+
+
+/// The hash function every tree is built with
+pub const HASH_FUNCTION: &'static str = "SHA-256";
+
+/// `PUT` and `DELETE` take the expected root hash in an `If-Match` header
+pub const FEATURE_CONDITIONAL_CHANGES: &'static str = "conditional-changes";
+
+/// Changes are kept with their signatures, and sent with `encode_signed_changes`
+pub const FEATURE_SIGNED_CHANGES: &'static str = "signed-changes";
+
+/// Several files are proven at once with a `MultiProof`
+pub const FEATURE_MULTI_PROOFS: &'static str = "multi-proofs";
+
+/// Parts of files are sent for a `Range` header
+pub const FEATURE_RANGES: &'static str = "ranges";
+
+/// Empty slots can be proven, which depends on the tree format
+pub const FEATURE_ABSENCE_PROOFS: &'static str = "absence-proofs";
+
+/// Runs of chunks can be proven with a `ChunkProof`, which depends on the tree format
+pub const FEATURE_CHUNK_PROOFS: &'static str = "chunk-proofs";
+
+/// The features a client can't verify a server without
+pub const REQUIRED_FEATURES: &'static [&'static str] = &[
+    FEATURE_CONDITIONAL_CHANGES,
+    FEATURE_SIGNED_CHANGES,
+    FEATURE_MULTI_PROOFS
+];
+
+
+/// What a server supports, so that a client can tell if it is able to verify it
+/// before sending it anything.
+///
+/// Encoded as the protocol version as a 32-bit integer, the tree format's
+/// version, the depth of the tree, the length of the hash function's name
+/// followed by the name, and the number of features followed by the length and
+/// name of every feature.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Info {
+    pub version: u32,
+
+    /// The version of the tree format, kept as a number so that a format this
+    /// side doesn't know can be reported
+    pub format: u8,
+
+    /// The depth of the tree, which is the number of bits in a `FileID`
+    pub depth: u8,
+
+    pub hash_function: String,
+
+    pub features: Vec<String>,
+}
+
+
+/// Why a client can't verify a server
+#[derive(Debug)]
+pub enum Incompatibility {
+    /// The server speaks another version of the protocol
+    Version(u32),
+
+    /// The server uses a tree format this client does not support
+    UnknownTreeFormat(u8),
+
+    /// The server was built with another width of file IDs
+    Depth(u8),
+
+    /// The server hashes its tree with another function
+    HashFunction(String),
+
+    /// The server lacks a feature the client needs
+    MissingFeature(&'static str),
+
+    /// The server's tree is in another format than when the client saved its root hash,
+    /// so the client's root hash can't be verified against it
+    FormatChanged {
+        saved: TreeFormat,
+        server: TreeFormat,
+    },
+}
+
+
+impl Info {
+    /// Describe a server built like this crate, with a tree in some format
+    pub fn new(format: TreeFormat) -> Info {
+        let mut features: Vec<String> = [
+            FEATURE_CONDITIONAL_CHANGES,
+            FEATURE_SIGNED_CHANGES,
+            FEATURE_MULTI_PROOFS,
+            FEATURE_RANGES
+        ].iter().map(|&feature| feature.to_owned()).collect();
+
+        if format.supports_absence_proofs() {
+            features.push(FEATURE_ABSENCE_PROOFS.to_owned());
+        }
+
+        if format.supports_chunk_proofs() {
+            features.push(FEATURE_CHUNK_PROOFS.to_owned());
+        }
+
+        Info {
+            version: VERSION,
+            format: format.version(),
+            depth: FILE_ID_BITS,
+            hash_function: HASH_FUNCTION.to_owned(),
+            features,
+        }
+    }
+
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = to_big_endian(self.version as u64, 4);
+
+        bytes.push(self.format);
+        bytes.push(self.depth);
+
+        push_name(&mut bytes, &self.hash_function);
+
+        bytes.push(self.features.len() as u8);
+        for feature in &self.features {
+            push_name(&mut bytes, feature);
+        }
+
+        bytes
+    }
+
+    /// Returns `None` unless the bytes are exactly one encoded `Info`
+    pub fn from_bytes(bytes: &[u8]) -> Option<Info> {
+        let mut reader = bytes;
+
+        let version = from_big_endian(take(&mut reader, 4)?) as u32;
+
+        let format = take(&mut reader, 1)?[0];
+        let depth = take(&mut reader, 1)?[0];
+        let hash_function = take_name(&mut reader)?;
+
+        let count = take(&mut reader, 1)?[0];
+        let features = (0..count)
+            .map(|_| take_name(&mut reader))
+            .collect::<Option<Vec<String>>>()?;
+
+        if !reader.is_empty() {
+            return None;
+        }
+
+        Some(Info {
+            version,
+            format,
+            depth,
+            hash_function,
+            features,
+        })
+    }
+
+
+    /// Returns true if the server has a feature
+    pub fn supports(&self, feature: &str) -> bool {
+        self.features.iter().any(|supported| supported == feature)
+    }
+
+    /// Check that a client built like this crate can verify the server, and
+    /// return the format of its tree
+    pub fn check(&self) -> Result<TreeFormat, Incompatibility> {
+        if self.version != VERSION {
+            return Err(Incompatibility::Version(self.version));
+        }
+
+        let format = TreeFormat::from_version(self.format)
+            .ok_or(Incompatibility::UnknownTreeFormat(self.format))?;
+
+        if self.depth != FILE_ID_BITS {
+            return Err(Incompatibility::Depth(self.depth));
+        }
+
+        if self.hash_function != HASH_FUNCTION {
+            return Err(Incompatibility::HashFunction(self.hash_function.clone()));
+        }
+
+        if let Some(feature) = REQUIRED_FEATURES.iter().find(|feature| !self.supports(feature)) {
+            return Err(Incompatibility::MissingFeature(feature));
+        }
+
+        Ok(format)
+    }
+}
+
+
+impl fmt::Display for Incompatibility {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Incompatibility::Version(version) =>
+                write!(f, "the server speaks protocol version {}, but this client speaks version {}", version, VERSION),
+
+            Incompatibility::UnknownTreeFormat(format) =>
+                write!(f, "the server's tree is in format {}, which this client does not know", format),
+
+            Incompatibility::Depth(depth) =>
+                write!(f, "the server uses {}-bit file IDs, but this client was built with {}-bit file IDs", depth, FILE_ID_BITS),
+
+            Incompatibility::HashFunction(ref function) =>
+                write!(f, "the server hashes with {}, but this client only knows {}", function, HASH_FUNCTION),
+
+            Incompatibility::MissingFeature(feature) =>
+                write!(f, "the server does not support {}, which this client needs", feature),
+
+            Incompatibility::FormatChanged { saved, server } =>
+                write!(f, "the server's tree is in format {}, but was in format {} when this profile saved its root hash; \
+                           use a new profile to start over with the server's tree", server.version(), saved.version()),
+        }
+    }
+}
+
+
+fn take<'a>(bytes: &mut &'a [u8], count: usize) -> Option<&'a [u8]> {
+    if bytes.len() < count {
+        return None;
+    }
+
+    let (head, tail) = bytes.split_at(count);
+    *bytes = tail;
+    Some(head)
+}
+
+fn take_name(bytes: &mut &[u8]) -> Option<String> {
+    let length = take(bytes, 1)?[0] as usize;
+    let name = take(bytes, length)?;
+
+    String::from_utf8(name.to_vec()).ok()
+}
+
+fn push_name(bytes: &mut Vec<u8>, name: &str) {
+    bytes.push(name.len() as u8);
+    bytes.extend_from_slice(name.as_bytes());
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn info_round_trip() {
+        for &format in &[TreeFormat::V1, TreeFormat::V2, TreeFormat::V3] {
+            let info = Info::new(format);
+            let bytes = info.to_bytes();
+
+            assert_eq!(Some(info.clone()), Info::from_bytes(&bytes));
+            assert_eq!(format, info.check().unwrap());
+
+            assert_eq!(None, Info::from_bytes(&bytes[..bytes.len() - 1]));
+
+            let mut longer = bytes.clone();
+            longer.push(0);
+            assert_eq!(None, Info::from_bytes(&longer));
+        }
+    }
+
+    #[test]
+    fn features_depend_on_the_format() {
+        assert!(!Info::new(TreeFormat::V1).supports(FEATURE_ABSENCE_PROOFS));
+        assert!(Info::new(TreeFormat::V2).supports(FEATURE_ABSENCE_PROOFS));
+        assert!(!Info::new(TreeFormat::V2).supports(FEATURE_CHUNK_PROOFS));
+        assert!(Info::new(TreeFormat::V3).supports(FEATURE_CHUNK_PROOFS));
+    }
+
+    #[test]
+    fn refuse_incompatible_servers() {
+        let mut info = Info::new(TreeFormat::V3);
+        info.version += 1;
+        assert!(match info.check() { Err(Incompatibility::Version(_)) => true, _ => false });
+
+        let mut info = Info::new(TreeFormat::V3);
+        info.format = 9;
+        assert!(match info.check() { Err(Incompatibility::UnknownTreeFormat(9)) => true, _ => false });
+
+        let mut info = Info::new(TreeFormat::V3);
+        info.features.retain(|feature| feature != FEATURE_SIGNED_CHANGES);
+        assert!(match info.check() { Err(Incompatibility::MissingFeature(FEATURE_SIGNED_CHANGES)) => true, _ => false });
+    }
+
+    #[test]
+    fn describe_changed_formats() {
+        let changed = Incompatibility::FormatChanged {
+            saved: TreeFormat::V2,
+            server: TreeFormat::V3,
+        };
+
+        assert!(changed.to_string().starts_with("the server's tree is in format 3, but was in format 2"));
+    }
+}
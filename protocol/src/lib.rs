@@ -0,0 +1,45 @@
+//! What the server and the client agree on: the type of file IDs, the paths of
+//! the server's routes and how everything sent between them is encoded.
+//!
+//! Both depend on this crate, so a change to the protocol can't be made on one
+//! side only.
+
+extern crate file_hash;
+
+
+pub mod routes;
+
+mod wire;
+pub use wire::*;
+
+mod info;
+pub use info::*;
+
+
+pub use file_hash::{
+    FileID,
+    Hash,
+    TreeFormat,
+    FILE_ID_BITS
+};
+
+/// Every integer sent is big-endian, encoded and decoded with these
+pub use file_hash::{
+    from_big_endian,
+    to_big_endian
+};
+
+/// Proofs are sent encoded with their `to_bytes`, and decoded with their
+/// `from_bytes`
+pub use file_hash::{
+    ChunkProof,
+    ConsistencyProof,
+    MerkleProof,
+    MultiProof,
+    ProofError
+};
+
+
+/// The version of the protocol. Increased whenever a change to the routes or
+/// to an encoding would break a client or server built for an older version.
+pub const VERSION: u32 = 1;
@@ -0,0 +1,57 @@
+//! Paths of the server's routes, relative to `MOUNT`.
+//!
+//! Rocket only takes literals in its route attributes, so the server spells
+//! these paths out again. Change them in both places.
+
+use super::{
+    FileID,
+    Hash
+};
+
+
+/// Where the server mounts its routes
+pub const MOUNT: &'static str = "/file";
+
+/// What the server supports, encoded as an `Info`
+pub const INFO: &'static str = "/info";
+
+/// The root hash of the server's tree, encoded with `encode_root`
+pub const VERIFY_ROOT: &'static str = "/verify/root";
+
+/// The format of the server's tree, encoded with `encode_format`
+pub const VERIFY_FORMAT: &'static str = "/verify/format";
+
+/// Takes file IDs encoded with `encode_ids`, and responds with a `MultiProof`
+pub const VERIFY_BATCH: &'static str = "/verify/batch";
+
+
+/// A file, which is uploaded, downloaded or removed
+pub fn file(file: FileID) -> String {
+    format!("/{id}", id = file)
+}
+
+/// The `MerkleProof` of a file
+pub fn verify_file(file: FileID) -> String {
+    format!("/verify/{id}", id = file)
+}
+
+/// The `MerkleProof` of an empty slot, if a file is absent
+pub fn verify_absent(file: FileID) -> String {
+    format!("/verify/absent/{id}", id = file)
+}
+
+/// The `ChunkProof` of a run of chunks of a file
+pub fn verify_chunks(file: FileID, first: u64, count: u64) -> String {
+    format!("/verify/chunks/{id}/{first}/{count}", id = file, first = first, count = count)
+}
+
+/// The `ConsistencyProof` of all changes made since the tree had a root hash
+pub fn verify_consistency(root: &Hash) -> String {
+    format!("/verify/consistency/{root}", root = root.to_hex())
+}
+
+/// All changes made since the tree had a root hash, and their signatures,
+/// encoded with `encode_signed_changes`
+pub fn verify_changes(root: &Hash) -> String {
+    format!("/verify/changes/{root}", root = root.to_hex())
+}
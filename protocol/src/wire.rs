@@ -0,0 +1,232 @@
+//! How headers and responses other than proofs are encoded. All integers are
+//! big-endian.
+
+use super::{
+    ConsistencyProof,
+    FileID,
+    Hash,
+    ProofError,
+    TreeFormat,
+    from_big_endian,
+    to_big_endian
+};
+
+
+/// The header holding the root hash a change was computed against
+pub const EXPECTED_ROOT_HEADER: &'static str = "If-Match";
+
+/// The header holding the signature of a change
+pub const CHANGE_SIGNATURE_HEADER: &'static str = "Change-Signature";
+
+/// The most file IDs the server proves at once. Longer lists get `400`.
+pub const MAX_BATCH_IDS: usize = 4096;
+
+/// The length of a signature: the device's Ed25519 public key followed by the
+/// signature itself
+pub const SIGNATURE_BYTES: usize = 32 + 64;
+
+
+#[derive(Debug)]
+pub enum FormatError {
+    /// The format is not a single byte
+    InvalidLength,
+
+    /// The format has a version this side does not support
+    UnknownVersion(u8),
+}
+
+
+/// A root hash is sent as its 32 raw bytes
+pub fn encode_root(root: &Hash) -> Vec<u8> {
+    root.as_bytes().to_vec()
+}
+
+pub fn decode_root(bytes: &[u8]) -> Option<Hash> {
+    if bytes.len() != Hash::BYTES {
+        return None;
+    }
+
+    Some(Hash::from_bytes(bytes))
+}
+
+
+/// A tree format is sent as a single byte, its version
+pub fn encode_format(format: TreeFormat) -> Vec<u8> {
+    vec![format.version()]
+}
+
+pub fn decode_format(bytes: &[u8]) -> Result<TreeFormat, FormatError> {
+    if bytes.len() != 1 {
+        return Err(FormatError::InvalidLength);
+    }
+
+    TreeFormat::from_version(bytes[0])
+        .ok_or(FormatError::UnknownVersion(bytes[0]))
+}
+
+
+/// A list of file IDs is sent as 64-bit integers, whatever the width of a `FileID`
+pub fn encode_ids(ids: &[FileID]) -> Vec<u8> {
+    ids.iter()
+        .flat_map(|&id| to_big_endian(id as u64, 8))
+        .collect()
+}
+
+/// Returns `None` if the length is not a multiple of 8, or an ID is too wide
+/// to be a `FileID`
+pub fn decode_ids(bytes: &[u8]) -> Option<Vec<FileID>> {
+    if bytes.len() % 8 != 0 {
+        return None;
+    }
+
+    bytes.chunks(8)
+        .map(from_big_endian)
+        .map(|id| if id > FileID::max_value() as u64 { None } else { Some(id as FileID) })
+        .collect()
+}
+
+
+/// The root hash in an `If-Match` header is quoted like an entity tag
+pub fn encode_expected_root(root: &Hash) -> String {
+    format!("\"{}\"", root.to_hex())
+}
+
+/// Takes the root hash in hexadecimal, quoted or not
+pub fn decode_expected_root(header: &str) -> Option<Hash> {
+    Hash::from_hex(header.trim().trim_matches('"'))
+}
+
+
+/// A signature is sent in hexadecimal
+pub fn encode_signature(signature: &[u8]) -> String {
+    to_hex(signature)
+}
+
+pub fn decode_signature(header: &str) -> Option<Vec<u8>> {
+    from_hex(header.trim())
+        .filter(|signature| signature.len() == SIGNATURE_BYTES)
+}
+
+
+/// Changes are sent as their number, the length of every signature followed by
+/// the signature (`0` if the change was not signed), and the `ConsistencyProof`
+/// of all of them
+pub fn encode_signed_changes(signatures: &[Option<&[u8]>], proof: &ConsistencyProof) -> Vec<u8> {
+    let mut bytes = to_big_endian(signatures.len() as u64, 8);
+
+    for signature in signatures {
+        let signature = signature.unwrap_or(&[]);
+
+        bytes.push(signature.len() as u8);
+        bytes.extend_from_slice(signature);
+    }
+
+    bytes.extend_from_slice(&proof.to_bytes());
+    bytes
+}
+
+/// Returns the signatures and the proof. The proof is not checked to hold as
+/// many changes as there are signatures.
+pub fn decode_signed_changes(bytes: &[u8]) -> Result<(Vec<Option<Vec<u8>>>, ConsistencyProof), ProofError> {
+    if bytes.len() < 8 {
+        return Err(ProofError::InvalidLength);
+    }
+
+    let count = from_big_endian(&bytes[..8]);
+    let mut rest = &bytes[8..];
+    let mut signatures = Vec::new();
+
+    for _ in 0..count {
+        let length = match rest.first() {
+            Some(&length) => length as usize,
+            None => return Err(ProofError::InvalidLength)
+        };
+
+        if rest.len() < 1 + length {
+            return Err(ProofError::InvalidLength);
+        }
+
+        signatures.push(if length == 0 { None } else { Some(rest[1..1 + length].to_vec()) });
+        rest = &rest[1 + length..];
+    }
+
+    let proof = ConsistencyProof::from_bytes(rest)?;
+
+    Ok((signatures, proof))
+}
+
+
+/// Encode bytes in lowercase hexadecimal
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+pub fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if hex.len() % 2 != 0 || !hex.is_ascii() {
+        return None;
+    }
+
+    (0..hex.len() / 2)
+        .map(|i| u8::from_str_radix(&hex[2 * i..2 * i + 2], 16).ok())
+        .collect()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use file_hash::{
+        LeafChange,
+        MerkleTree
+    };
+
+    use super::super::FILE_ID_BITS;
+
+    #[test]
+    fn ids_round_trip() {
+        let ids = vec![0, 1, 7, FileID::max_value()];
+
+        assert_eq!(Some(ids.clone()), decode_ids(&encode_ids(&ids)));
+        assert_eq!(Some(Vec::new()), decode_ids(&[]));
+        assert_eq!(None, decode_ids(&[0; 7]));
+
+        // Too wide to be a `FileID`, unless they are 64 bits wide
+        if let Some(id) = (FileID::max_value() as u64).checked_add(1) {
+            assert_eq!(None, decode_ids(&to_big_endian(id, 8)));
+        }
+    }
+
+    #[test]
+    fn signed_changes_round_trip() {
+        let format = TreeFormat::V3;
+        let mut tree = MerkleTree::new(FILE_ID_BITS, format);
+
+        let mut changes = Vec::new();
+        for (file, data) in vec![(1, &b"one"[..]), (2, &b"two"[..])] {
+            changes.push(LeafChange {
+                proof: tree.proof(file).unwrap(),
+                old: format.empty_leaf(),
+                new: format.leaf(data)
+            });
+
+            tree.insert(file, format.leaf(data)).unwrap();
+        }
+
+        let proof = ConsistencyProof::new(changes);
+        let signature = vec![5; SIGNATURE_BYTES];
+        let signatures = vec![Some(&signature[..]), None];
+
+        let bytes = encode_signed_changes(&signatures, &proof);
+        let (decoded, decoded_proof) = decode_signed_changes(&bytes).unwrap();
+
+        assert_eq!(vec![Some(signature.clone()), None], decoded);
+        assert_eq!(proof, decoded_proof);
+
+        assert!(decode_signed_changes(&bytes[..bytes.len() - 1]).is_err());
+        assert!(decode_signed_changes(&bytes[..8 + 1 + 10]).is_err());
+        assert!(decode_signed_changes(&[0; 7]).is_err());
+    }
+}
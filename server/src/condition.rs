@@ -0,0 +1,53 @@
+
+use protocol::{
+    self,
+    Hash,
+    EXPECTED_ROOT_HEADER
+};
+
+use rocket::{
+    Outcome,
+    http::Status,
+    request::{
+        self,
+        FromRequest,
+        Request
+    }
+};
+
+
+/// The root hash a change was computed against, given in hexadecimal in an
+/// `If-Match` header, optionally quoted like an entity tag.
+///
+/// A change with an expected root hash is only made if the tree still has that
+/// root hash, so that two clients changing the tree at the same time can't
+/// overwrite each other's changes.
+pub struct ExpectedRoot(Option<Hash>);
+
+
+impl ExpectedRoot {
+    /// Returns true if the change may be made to a tree with some root hash
+    pub fn matches(&self, root: &Hash) -> bool {
+        match self.0 {
+            Some(ref expected) => expected == root,
+            None => true
+        }
+    }
+}
+
+
+impl<'a, 'r> FromRequest<'a, 'r> for ExpectedRoot {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<ExpectedRoot, ()> {
+        let header = match request.headers().get_one(EXPECTED_ROOT_HEADER) {
+            Some(header) => header,
+            None => return Outcome::Success(ExpectedRoot(None))
+        };
+
+        match protocol::decode_expected_root(header) {
+            Some(root) => Outcome::Success(ExpectedRoot(Some(root))),
+            None => Outcome::Failure((Status::BadRequest, ()))
+        }
+    }
+}
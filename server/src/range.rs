@@ -0,0 +1,201 @@
+
+use rocket::{
+    Outcome,
+    http::Status,
+    request::{
+        self,
+        FromRequest,
+        Request
+    },
+    response::{
+        self,
+        Responder,
+        Response
+    }
+};
+
+use std::io::Read;
+
+
+/// The `Range` header of a request.
+///
+/// Only a single range of bytes is supported. Requests without the header, or
+/// with one asking for anything else, get the whole file, as HTTP allows.
+pub struct ByteRange(Option<Spec>);
+
+enum Spec {
+    /// The first byte and, optionally, the last byte
+    From(u64, Option<u64>),
+
+    /// The number of bytes at the end of the file
+    Suffix(u64)
+}
+
+
+/// A file, or a part of it
+pub enum FileResponse {
+    Full(Box<Read + Send>),
+
+    /// The bytes from `start` until, but not including, `end` of a file of some length
+    Partial {
+        data: Box<Read + Send>,
+        start: u64,
+        end: u64,
+        length: u64
+    },
+
+    /// The range asked for is outside the file
+    Unsatisfiable {
+        length: u64
+    }
+}
+
+
+impl ByteRange {
+    /// Find the bytes asked for in a file of some length, as the first byte and the
+    /// byte after the last. Returns `Ok(None)` for the whole file, and `Err` if no
+    /// byte asked for is in the file.
+    pub fn resolve(&self, length: u64) -> Result<Option<(u64, u64)>, ()> {
+        match self.0 {
+            None => Ok(None),
+
+            Some(Spec::From(first, last)) => {
+                if first >= length {
+                    return Err(());
+                }
+
+                let end = match last {
+                    Some(last) if last < length => last + 1,
+                    _ => length
+                };
+
+                Ok(Some((first, end)))
+            }
+
+            Some(Spec::Suffix(count)) => {
+                if count == 0 || length == 0 {
+                    return Err(());
+                }
+
+                Ok(Some((length - count.min(length), length)))
+            }
+        }
+    }
+}
+
+
+impl<'a, 'r> FromRequest<'a, 'r> for ByteRange {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<ByteRange, ()> {
+        let spec = request.headers()
+            .get_one("Range")
+            .and_then(parse_range);
+
+        Outcome::Success(ByteRange(spec))
+    }
+}
+
+
+impl<'r> Responder<'r> for FileResponse {
+    fn respond_to(self, _: &Request) -> response::Result<'r> {
+        match self {
+            FileResponse::Full(data) => {
+                Response::build()
+                    .raw_header("Accept-Ranges", "bytes")
+                    .streamed_body(data)
+                    .ok()
+            }
+
+            FileResponse::Partial { data, start, end, length } => {
+                Response::build()
+                    .status(Status::PartialContent)
+                    .raw_header("Accept-Ranges", "bytes")
+                    .raw_header("Content-Range", format!("bytes {}-{}/{}", start, end - 1, length))
+                    .streamed_body(data)
+                    .ok()
+            }
+
+            FileResponse::Unsatisfiable { length } => {
+                Response::build()
+                    .status(Status::RangeNotSatisfiable)
+                    .raw_header("Content-Range", format!("bytes */{}", length))
+                    .ok()
+            }
+        }
+    }
+}
+
+
+/// Parse a header such as `bytes=0-499`, `bytes=500-` or `bytes=-500`
+fn parse_range(header: &str) -> Option<Spec> {
+    let header = header.trim();
+
+    // Several ranges in one request are answered with the whole file
+    if !header.starts_with("bytes=") || header.contains(',') {
+        return None;
+    }
+
+    let range = &header["bytes=".len()..];
+
+    let mut bounds = range.splitn(2, '-');
+    let first = bounds.next()?.trim();
+    let last = bounds.next()?.trim();
+
+    if first.is_empty() {
+        return last.parse().ok().map(Spec::Suffix);
+    }
+
+    let first = first.parse().ok()?;
+
+    if last.is_empty() {
+        return Some(Spec::From(first, None));
+    }
+
+    match last.parse() {
+        Ok(last) if last >= first => Some(Spec::From(first, Some(last))),
+        _ => None
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn resolve(header: &str, length: u64) -> Result<Option<(u64, u64)>, ()> {
+        ByteRange(parse_range(header)).resolve(length)
+    }
+
+    #[test]
+    fn resolve_ranges() {
+        assert_eq!(Ok(Some((0, 500))), resolve("bytes=0-499", 1000));
+        assert_eq!(Ok(Some((500, 1000))), resolve("bytes=500-", 1000));
+        assert_eq!(Ok(Some((0, 1000))), resolve("bytes=0-", 1000));
+        assert_eq!(Ok(Some((999, 1000))), resolve("bytes=999-999", 1000));
+        assert_eq!(Ok(Some((900, 1000))), resolve("bytes=900-2000", 1000));
+        assert_eq!(Ok(Some((500, 1000))), resolve("bytes=-500", 1000));
+    }
+
+    #[test]
+    fn clamp_long_suffixes() {
+        assert_eq!(Ok(Some((0, 1000))), resolve("bytes=-2000", 1000));
+    }
+
+    #[test]
+    fn refuse_ranges_outside_the_file() {
+        assert_eq!(Err(()), resolve("bytes=-0", 1000));
+        assert_eq!(Err(()), resolve("bytes=1000-", 1000));
+        assert_eq!(Err(()), resolve("bytes=0-", 0));
+        assert_eq!(Err(()), resolve("bytes=-500", 0));
+    }
+
+    #[test]
+    fn serve_the_whole_file_for_other_ranges() {
+        assert_eq!(Ok(None), resolve("bytes=5-3", 1000));
+        assert_eq!(Ok(None), resolve("bytes=0-1,5-6", 1000));
+        assert_eq!(Ok(None), resolve("items=0-1", 1000));
+        assert_eq!(Ok(None), resolve("bytes=a-b", 1000));
+        assert_eq!(Ok(None), resolve("bytes=", 1000));
+    }
+}
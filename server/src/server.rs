@@ -4,62 +4,356 @@
 extern crate rocket;
 
 extern crate file_hash;
+extern crate protocol;
+extern crate tempfile;
+
+use protocol::{
+    Hash,
+    TreeFormat,
+    routes
+};
 
 use rocket::{
+    Data,
+    Rocket,
     State,
     http::Status,
-    response::status::Custom as CustomStatus
+    response::{
+        Failure,
+        status::Custom as CustomStatus
+    }
 };
 
-use std::sync::{
-    Arc,
-    RwLock
+use std::{
+    io::{
+        self,
+        Read
+    },
+    path::Path,
+    sync::{
+        Arc,
+        RwLock
+    }
 };
 
-mod file_cache;
-use file_cache::{
-    FileCache,
+mod storage;
+use storage::{
+    Storage,
+    History,
     FileID
 };
 
+mod condition;
+use condition::ExpectedRoot;
+
+mod range;
+use range::{
+    ByteRange,
+    FileResponse
+};
+
+mod signature;
+use signature::ChangeSignature;
+
+
+type Files = Arc<RwLock<History>>;
+
+/// The largest file that can be uploaded, in bytes
+struct UploadLimit(u64);
 
-type Files = Arc<RwLock<FileCache>>;
+/// Used unless `max_upload` is set, 1 GiB
+const DEFAULT_UPLOAD_LIMIT: u64 = 1 << 30;
 
 
+/// Streams the file, or the part of it asked for in a `Range` header, so that
+/// large files are never held in memory
 #[get("/<file>")]
-fn get_file(files: State<Files>, file: FileID) -> Option<Vec<u8>> {
-    files.read().unwrap()
-        .get(file)
+fn get_file(files: State<Files>, file: FileID, range: ByteRange) -> io::Result<Option<FileResponse>> {
+    let files = files.read().unwrap();
+
+    let length = match files.size(file)? {
+        Some(length) => length,
+        None => return Ok(None)
+    };
+
+    match range.resolve(length) {
+        Ok(None) => Ok(files.get(file)?.map(FileResponse::Full)),
+
+        Ok(Some((start, end))) => Ok(files.get_range(file, start, end - start)?
+            .map(|data| FileResponse::Partial {
+                data,
+                start,
+                end,
+                length
+            })),
+
+        Err(()) => Ok(Some(FileResponse::Unsatisfiable {
+            length
+        }))
+    }
 }
 
+/// Stores a file. With an `If-Match` header, responds with `412` unless the
+/// tree has the root hash given in it. A `Change-Signature` header is kept
+/// with the change in the history. Responds with `413` if the file is larger
+/// than the upload limit.
+///
+/// The file is received before the storage is locked, so that a slow upload
+/// doesn't hold up everyone else.
 #[put("/<file>", data="<data>")]
-fn upload_file(files: State<Files>, file: FileID, data: Vec<u8>) -> CustomStatus<()> {
-    match files.write().unwrap()
-        .insert(file, data) {
-        Some(_) => CustomStatus(Status::Ok, ()),
-        None => CustomStatus(Status::Created, ())
+fn upload_file(files: State<Files>, limit: State<UploadLimit>, file: FileID, expected: ExpectedRoot, signature: ChangeSignature, data: Data) -> io::Result<CustomStatus<()>> {
+    let stager = files.read().unwrap().stager();
+    let staged = stager.stage(&mut data.open().take(limit.0 + 1))?;
+
+    if staged.length() > limit.0 {
+        return Ok(CustomStatus(Status::PayloadTooLarge, ()));
+    }
+
+    let mut files = files.write().unwrap();
+
+    if !expected.matches(&files.root()) {
+        return Ok(CustomStatus(Status::PreconditionFailed, ()));
     }
+
+    match files.put_signed(file, staged, signature.into_bytes())? {
+        Some(_) => Ok(CustomStatus(Status::Ok, ())),
+        None => Ok(CustomStatus(Status::Created, ()))
+    }
+}
+
+/// Removes a file, leaving an empty slot in the tree. Responds with `404` if
+/// there is no such file, and `412` like `upload_file`.
+#[delete("/<file>")]
+fn delete_file(files: State<Files>, file: FileID, expected: ExpectedRoot, signature: ChangeSignature) -> io::Result<CustomStatus<()>> {
+    let mut files = files.write().unwrap();
+
+    if !expected.matches(&files.root()) {
+        return Ok(CustomStatus(Status::PreconditionFailed, ()));
+    }
+
+    match files.delete_signed(file, signature.into_bytes())? {
+        Some(_) => Ok(CustomStatus(Status::Ok, ())),
+        None => Ok(CustomStatus(Status::NotFound, ()))
+    }
+}
+
+/// Responds with an encoded `Info`, so that clients can tell if they are able
+/// to verify this server
+#[get("/info")]
+fn get_info(files: State<Files>) -> Vec<u8> {
+    protocol::Info::new(files.read().unwrap().tree().format())
+        .to_bytes()
+}
+
+#[get("/verify/format")]
+fn get_tree_format(files: State<Files>) -> Vec<u8> {
+    protocol::encode_format(files.read().unwrap()
+        .tree()
+        .format())
 }
 
 #[get("/verify/root")]
 fn get_root_hash(files: State<Files>) -> Vec<u8> {
-    files.read().unwrap()
-        .root_hash()
+    protocol::encode_root(&files.read().unwrap().root())
 }
 
+/// Responds with an encoded `MerkleProof`
 #[get("/verify/<file>")]
 fn get_dependencies(files: State<Files>, file: FileID) -> Option<Vec<u8>> {
     files.read().unwrap()
-        .hash_dependencies(file)
+        .proof(file)
+        .map(|proof| proof.to_bytes())
+}
+
+/// Responds with an encoded `MerkleProof`, if the file is absent
+#[get("/verify/absent/<file>")]
+fn get_absence_proof(files: State<Files>, file: FileID) -> Option<Vec<u8>> {
+    files.read().unwrap()
+        .absence_proof(file)
+        .map(|proof| proof.to_bytes())
+}
+
+
+/// Responds with an encoded `ChunkProof` of a run of chunks of a file
+#[get("/verify/chunks/<file>/<first>/<count>")]
+fn get_chunk_proof(files: State<Files>, file: FileID, first: u64, count: u64) -> io::Result<Option<Vec<u8>>> {
+    files.read().unwrap()
+        .chunk_proof(file, first, count)
+        .map(|proof| proof.map(|proof| proof.to_bytes()))
+}
+
+
+/// Responds with an encoded `ConsistencyProof` of all changes made since the
+/// tree had a root hash, given in hexadecimal
+#[get("/verify/consistency/<root>")]
+fn get_consistency_proof(files: State<Files>, root: String) -> Option<Vec<u8>> {
+    let root = Hash::from_hex(&root)?;
+
+    files.read().unwrap()
+        .since(&root)
+        .map(|proof| proof.to_bytes())
+}
+
+
+/// Responds with the signatures of all changes made since the tree had a root
+/// hash, followed by their encoded `ConsistencyProof`. Both are read at once,
+/// so they always describe the same changes.
+#[get("/verify/changes/<root>")]
+fn get_signed_changes(files: State<Files>, root: String) -> Option<Vec<u8>> {
+    let root = Hash::from_hex(&root)?;
+
+    let files = files.read().unwrap();
+    let signatures = files.signatures_since(&root)?;
+    let proof = files.since(&root)?;
+
+    Some(protocol::encode_signed_changes(&signatures, &proof))
+}
+
+
+/// Takes a list of file IDs as big-endian 64-bit integers and responds with an
+/// encoded `MultiProof` for all of them. Responds with `400` for more than
+/// `MAX_BATCH_IDS` files.
+#[post("/verify/batch", data="<data>")]
+fn get_multi_proof(files: State<Files>, data: Data) -> Result<Vec<u8>, Failure> {
+    let mut bytes = Vec::new();
+    data.open()
+        .take(protocol::MAX_BATCH_IDS as u64 * 8 + 1)
+        .read_to_end(&mut bytes)
+        .map_err(|_| Failure(Status::BadRequest))?;
+
+    let ids = protocol::decode_ids(&bytes)
+        .filter(|ids| ids.len() <= protocol::MAX_BATCH_IDS)
+        .ok_or(Failure(Status::BadRequest))?;
+
+    files.read().unwrap()
+        .multi_proof(&ids)
+        .map(|proof| proof.to_bytes())
+        .ok_or(Failure(Status::BadRequest))
 }
 
 fn main() {
-    let files: Files = Arc::new(RwLock::new(FileCache::new()));
+    let rocket = rocket::ignite();
+
+    // Where files are stored, set with `data_dir` in Rocket.toml or `ROCKET_DATA_DIR`
+    let data_dir = rocket.config()
+        .get_str("data_dir")
+        .unwrap_or("data")
+        .to_owned();
+
+    // One of `memory`, `filesystem` or `log`, set with `storage` in Rocket.toml or `ROCKET_STORAGE`
+    let backend = rocket.config()
+        .get_str("storage")
+        .unwrap_or("filesystem")
+        .to_owned();
+
+    // The version of the `TreeFormat`, set with `tree_format` in Rocket.toml or `ROCKET_TREE_FORMAT`.
+    // Only a new data directory takes it, an existing one must already be in that format.
+    let format = rocket.config().get_int("tree_format").ok().map(|version| {
+        TreeFormat::from_version(version as u8)
+            .filter(|format| format.version() as i64 == version)
+            .expect("tree_format must be 1, 2 or 3")
+    });
+
+    // The largest file that can be uploaded in bytes, set with `max_upload` in Rocket.toml or `ROCKET_MAX_UPLOAD`
+    let limit = match rocket.config().get_int("max_upload") {
+        Ok(limit) if limit >= 0 => limit as u64,
+        Ok(_) => panic!("max_upload must not be negative"),
+        Err(_) => DEFAULT_UPLOAD_LIMIT
+    };
 
-    rocket::ignite()
+    let storage = storage::open(&backend, Path::new(&data_dir), format)
+        .expect("Failed to open storage");
+
+    mount(rocket, storage, limit).launch();
+}
+
+
+/// Serve the files in a storage, taking uploads of up to `limit` bytes
+fn mount(rocket: Rocket, storage: History, limit: u64) -> Rocket {
+    let files: Files = Arc::new(RwLock::new(storage));
+
+    rocket
         .manage(files)
-        .mount("/file", routes![get_file, upload_file, get_root_hash, get_dependencies])
-        .launch();
+        .manage(UploadLimit(limit))
+        // The paths of these routes must match those in `protocol::routes`
+        .mount(routes::MOUNT, routes![
+            get_file,
+            upload_file,
+            delete_file,
+            get_info,
+            get_tree_format,
+            get_root_hash,
+            get_dependencies,
+            get_absence_proof,
+            get_chunk_proof,
+            get_consistency_proof,
+            get_signed_changes,
+            get_multi_proof
+        ])
 }
 
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use rocket::local::Client;
+    use storage::MemoryStorage;
+
+    /// Every path in `protocol::routes` must reach a route here
+    #[test]
+    fn serve_every_protocol_route() {
+        let mut storage = History::new(Box::new(MemoryStorage::new(TreeFormat::V3)));
+        let root = storage.root();
+        storage.put(1, &mut &b"stored"[..]).unwrap();
+
+        let client = Client::new(mount(rocket::ignite(), storage, 1024)).unwrap();
+        let path = |route: &str| format!("{}{}", routes::MOUNT, route);
+
+        let requests = vec![
+            client.get(path(routes::INFO)),
+            client.get(path(routes::VERIFY_ROOT)),
+            client.get(path(routes::VERIFY_FORMAT)),
+            client.post(path(routes::VERIFY_BATCH)).body(protocol::encode_ids(&[1, 3])),
+            client.get(path(&routes::file(1))),
+            client.get(path(&routes::verify_file(1))),
+            client.get(path(&routes::verify_absent(3))),
+            client.get(path(&routes::verify_chunks(1, 0, 1))),
+            client.get(path(&routes::verify_consistency(&root))),
+            client.get(path(&routes::verify_changes(&root))),
+            client.put(path(&routes::file(2))).body("uploaded"),
+            client.delete(path(&routes::file(2))),
+        ];
+
+        for request in requests {
+            let uri = request.inner().uri().to_string();
+            let status = request.dispatch().status();
+
+            assert!(status.code >= 200 && status.code < 300, "{} responded with {}", uri, status);
+        }
+    }
+
+    #[test]
+    fn refuse_too_many_ids() {
+        let storage = History::new(Box::new(MemoryStorage::new(TreeFormat::V3)));
+        let client = Client::new(mount(rocket::ignite(), storage, 1024)).unwrap();
+        let path = format!("{}{}", routes::MOUNT, routes::VERIFY_BATCH);
+
+        let ids: Vec<FileID> = (0..protocol::MAX_BATCH_IDS as u64 + 1)
+            .map(|id| id as FileID)
+            .collect();
+
+        let response = client.post(path).body(protocol::encode_ids(&ids)).dispatch();
+        assert_eq!(Status::BadRequest, response.status());
+    }
+
+    #[test]
+    fn refuse_large_uploads() {
+        let storage = History::new(Box::new(MemoryStorage::new(TreeFormat::V3)));
+        let client = Client::new(mount(rocket::ignite(), storage, 4)).unwrap();
+        let path = format!("{}{}", routes::MOUNT, routes::file(1));
+
+        assert_eq!(Status::PayloadTooLarge, client.put(path.clone()).body("12345").dispatch().status());
+        assert_eq!(Status::Created, client.put(path).body("1234").dispatch().status());
+    }
+}
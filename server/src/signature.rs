@@ -0,0 +1,47 @@
+
+use protocol::{
+    self,
+    CHANGE_SIGNATURE_HEADER
+};
+
+use rocket::{
+    Outcome,
+    http::Status,
+    request::{
+        self,
+        FromRequest,
+        Request
+    }
+};
+
+
+/// The signature of a change, given in hexadecimal in a `Change-Signature`
+/// header.
+///
+/// The server doesn't check signatures, it only keeps them with the history so
+/// that other devices can tell who made a change.
+pub struct ChangeSignature(Option<Vec<u8>>);
+
+
+impl ChangeSignature {
+    pub fn into_bytes(self) -> Option<Vec<u8>> {
+        self.0
+    }
+}
+
+
+impl<'a, 'r> FromRequest<'a, 'r> for ChangeSignature {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<ChangeSignature, ()> {
+        let header = match request.headers().get_one(CHANGE_SIGNATURE_HEADER) {
+            Some(header) => header,
+            None => return Outcome::Success(ChangeSignature(None))
+        };
+
+        match protocol::decode_signature(header) {
+            Some(signature) => Outcome::Success(ChangeSignature(Some(signature))),
+            None => Outcome::Failure((Status::BadRequest, ()))
+        }
+    }
+}
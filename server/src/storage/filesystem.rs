@@ -0,0 +1,460 @@
+
+use file_hash::{
+    ChunkTree,
+    Hash,
+    MerkleTree,
+    TreeFormat,
+    FILE_ID_BITS
+};
+
+use std::{
+    collections::BTreeSet,
+    fs::{
+        self,
+        File
+    },
+    io::{
+        self,
+        BufReader,
+        Read,
+        Seek,
+        SeekFrom,
+        Write
+    },
+    path::{
+        Path,
+        PathBuf
+    }
+};
+
+use super::{
+    Storage,
+    FileID,
+    Staged,
+    Stager,
+    copy_leaf,
+    kept_chunk_tree,
+    sync_directory
+};
+
+/// The name of the snapshot of the Merkle tree in the data directory
+const SNAPSHOT: &'static str = "tree.snapshot";
+
+/// The number of changes after which the snapshot is written again
+const SNAPSHOT_INTERVAL: usize = 100;
+
+/// The name of the directory holding the hashes of every file's chunks, in a
+/// file of the same name as the file
+const CHUNKS: &'static str = "chunks";
+
+/// Stores files on disk, one file per ID, in a single directory.
+///
+/// A snapshot of the Merkle tree is kept next to the files, so that the tree
+/// can be reloaded without reading every file. The snapshot is removed before
+/// the first change after it was written, and written again every
+/// `SNAPSHOT_INTERVAL` changes and when the storage is dropped, so if it exists
+/// it always matches the files. After a crash the tree is rebuilt instead.
+///
+/// If the format splits files into chunks, the hashes of a file's chunks are
+/// kept as well, so that chunk proofs don't need the whole file to be read.
+/// They are checked against the tree before they are used.
+pub struct FileSystemStorage {
+    directory: PathBuf,
+    files: BTreeSet<FileID>,
+    hashes: MerkleTree,
+    stager: Stager,
+
+    /// The number of changes made since the snapshot was written
+    unsaved: usize
+}
+
+impl FileSystemStorage {
+    /// Open a directory of files, creating the directory if needed.
+    ///
+    /// The Merkle tree is reloaded from the snapshot, or rebuilt from the
+    /// files if there is none or it has another format, so the root hash is
+    /// the same as before the server was restarted.
+    pub fn open<P: AsRef<Path>>(directory: P, format: TreeFormat) -> io::Result<FileSystemStorage> {
+        let directory = directory.as_ref().to_path_buf();
+        fs::create_dir_all(directory.join(CHUNKS))?;
+
+        let snapshot = match File::open(directory.join(SNAPSHOT)) {
+            Ok(file) => MerkleTree::load(BufReader::new(file)).ok()
+                .filter(|tree| tree.depth() == FILE_ID_BITS && tree.format() == format),
+            Err(_) => None
+        };
+
+        let mut files = BTreeSet::new();
+        let mut hashes = MerkleTree::new(FILE_ID_BITS, format);
+
+        for entry in fs::read_dir(&directory)? {
+            let path = entry?.path();
+
+            match file_id(&path) {
+                Some(file) => {
+                    if snapshot.is_none() {
+                        let (hash, chunks, _) = copy_leaf(format, &mut File::open(&path)?, &mut io::sink())?;
+                        hashes.insert(file as u64, hash).unwrap();
+                        save_chunks(&directory, file, &chunks)?;
+                    }
+
+                    files.insert(file);
+                }
+
+                // Left behind by a write that never completed
+                None if path.extension() == Some("tmp".as_ref()) => {
+                    fs::remove_file(&path)?;
+                }
+
+                None => {}
+            }
+        }
+
+        // Left behind by files that were removed, or writes that never completed
+        for entry in fs::read_dir(directory.join(CHUNKS))? {
+            let path = entry?.path();
+
+            if file_id(&path).map_or(true, |file| !files.contains(&file)) {
+                fs::remove_file(&path)?;
+            }
+        }
+
+        let rebuilt = snapshot.is_none();
+
+        let storage = FileSystemStorage {
+            stager: Stager::open(&directory, format)?,
+            directory,
+            files,
+            hashes: snapshot.unwrap_or(hashes),
+            unsaved: 0
+        };
+
+        if rebuilt {
+            storage.save_snapshot()?;
+        }
+
+        Ok(storage)
+    }
+
+
+    /// Remove the snapshot before the files are first changed after it was written
+    fn invalidate_snapshot(&self) -> io::Result<()> {
+        if self.unsaved > 0 {
+            return Ok(());
+        }
+
+        match fs::remove_file(self.directory.join(SNAPSHOT)) {
+            Ok(_) => sync_directory(&self.directory),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(e)
+        }
+    }
+
+    /// Count a change once it has been made, writing the snapshot again if
+    /// enough changes have been made since it was last written
+    fn changed(&mut self) -> io::Result<()> {
+        self.unsaved += 1;
+
+        if self.unsaved >= SNAPSHOT_INTERVAL {
+            self.save_snapshot()?;
+            self.unsaved = 0;
+        }
+
+        Ok(())
+    }
+
+    fn save_snapshot(&self) -> io::Result<()> {
+        let mut bytes = Vec::new();
+        self.hashes.save(&mut bytes, false)?;
+
+        write_atomic(&self.directory.join(SNAPSHOT), |file| file.write_all(&bytes))
+    }
+
+
+    fn file_path(&self, file: FileID) -> PathBuf {
+        self.directory.join(file.to_string())
+    }
+}
+
+impl Storage for FileSystemStorage {
+    fn get(&self, file: FileID) -> io::Result<Option<Box<Read + Send>>> {
+        match File::open(self.file_path(file)) {
+            Ok(data) => Ok(Some(Box::new(data))),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e)
+        }
+    }
+
+
+    fn size(&self, file: FileID) -> io::Result<Option<u64>> {
+        match fs::metadata(self.file_path(file)) {
+            Ok(metadata) => Ok(Some(metadata.len())),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e)
+        }
+    }
+
+
+    fn get_range(&self, file: FileID, offset: u64, length: u64) -> io::Result<Option<Box<Read + Send>>> {
+        match File::open(self.file_path(file)) {
+            Ok(mut data) => {
+                data.seek(SeekFrom::Start(offset))?;
+                Ok(Some(Box::new(data.take(length))))
+            }
+
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e)
+        }
+    }
+
+
+    fn stager(&self) -> Stager {
+        self.stager.clone()
+    }
+
+
+    /// The staged file is already synced to disk, so it only has to be renamed
+    fn put_staged(&mut self, file: FileID, staged: Staged) -> io::Result<Option<Hash>> {
+        self.invalidate_snapshot()?;
+
+        staged.file.persist(self.file_path(file))
+            .map_err(|e| e.error)?;
+        self.files.insert(file);
+        let old = self.hashes.insert(file as u64, staged.hash).unwrap();
+
+        sync_directory(&self.directory)?;
+
+        // If the server stops before they are saved, the old hashes no longer
+        // match the file and are not used
+        save_chunks(&self.directory, file, &staged.chunks)?;
+
+        self.changed()?;
+        Ok(old)
+    }
+
+
+    fn delete(&mut self, file: FileID) -> io::Result<Option<Hash>> {
+        if !self.files.contains(&file) {
+            return Ok(None);
+        }
+
+        self.invalidate_snapshot()?;
+
+        fs::remove_file(self.file_path(file))?;
+        self.files.remove(&file);
+        let old = self.hashes.remove(file as u64).unwrap();
+
+        sync_directory(&self.directory)?;
+
+        match fs::remove_file(chunks_path(&self.directory, file)) {
+            Ok(_) => {}
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e)
+        }
+
+        self.changed()?;
+        Ok(old)
+    }
+
+
+    fn list(&self) -> Vec<FileID> {
+        self.files.iter().cloned().collect()
+    }
+
+
+    fn tree(&self) -> &MerkleTree {
+        &self.hashes
+    }
+
+
+    fn chunk_tree(&self, file: FileID) -> io::Result<Option<ChunkTree>> {
+        let format = self.hashes.format();
+
+        if !format.supports_chunk_proofs() {
+            return Ok(None);
+        }
+
+        let length = match self.size(file)? {
+            Some(length) => length,
+            None => return Ok(None)
+        };
+
+        let kept = load_chunks(&self.directory, file)
+            .and_then(|chunks| kept_chunk_tree(&self.hashes, file, length, &chunks));
+
+        match kept {
+            Some(tree) => Ok(Some(tree)),
+            None => ChunkTree::read(format, &mut File::open(self.file_path(file))?).map(Some)
+        }
+    }
+}
+
+
+impl Drop for FileSystemStorage {
+    /// Write the snapshot of any changes made since it was last written. If
+    /// this fails, the tree is rebuilt from the files the next time.
+    fn drop(&mut self) {
+        if self.unsaved > 0 {
+            let _ = self.save_snapshot();
+        }
+    }
+}
+
+
+/// Return the ID of the file at a path, if its name is one. Only names written
+/// by `FileID::to_string` are IDs, so that `05` and `+5` are not taken for `5`.
+fn file_id(path: &Path) -> Option<FileID> {
+    let name = path.file_name()?.to_str()?;
+
+    name.parse::<FileID>().ok()
+        .filter(|file| file.to_string() == name)
+}
+
+fn chunks_path(directory: &Path, file: FileID) -> PathBuf {
+    directory.join(CHUNKS).join(file.to_string())
+}
+
+/// Keep the hashes of a file's chunks, one after another
+fn save_chunks(directory: &Path, file: FileID, chunks: &[Hash]) -> io::Result<()> {
+    if chunks.is_empty() {
+        return Ok(());
+    }
+
+    let bytes: Vec<u8> = chunks.iter()
+        .flat_map(|chunk| chunk.as_bytes().to_vec())
+        .collect();
+
+    write_atomic(&chunks_path(directory, file), |temporary| temporary.write_all(&bytes))
+}
+
+/// Return the hashes of a file's chunks, if they were kept
+fn load_chunks(directory: &Path, file: FileID) -> Option<Vec<Hash>> {
+    let mut bytes = Vec::new();
+    File::open(chunks_path(directory, file)).ok()?
+        .read_to_end(&mut bytes).ok()?;
+
+    if bytes.len() % Hash::BYTES != 0 {
+        return None;
+    }
+
+    Some(bytes.chunks(Hash::BYTES).map(Hash::from_bytes).collect())
+}
+
+
+/// Replace the contents of a file in a way that either fully succeeds or
+/// leaves the old file untouched, even if the server crashes. The new contents
+/// are written by `write`, whose result is returned.
+fn write_atomic<F, T>(path: &Path, write: F) -> io::Result<T>
+    where F: FnOnce(&mut File) -> io::Result<T>
+{
+    let temporary = path.with_extension("tmp");
+
+    let result = {
+        let mut file = File::create(&temporary)?;
+        let result = write(&mut file)?;
+        file.sync_all()?;
+        result
+    };
+
+    fs::rename(&temporary, path)?;
+
+    if let Some(directory) = path.parent() {
+        sync_directory(directory)?;
+    }
+
+    Ok(result)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::read_file;
+
+    use tempfile::tempdir;
+
+    #[test]
+    fn reopen_with_the_same_root() {
+        let directory = tempdir().unwrap();
+
+        let root = {
+            let mut storage = FileSystemStorage::open(directory.path(), TreeFormat::V3).unwrap();
+            storage.put(1, &mut &b"one"[..]).unwrap();
+            storage.put(2, &mut &b"two"[..]).unwrap();
+            storage.put(1, &mut &b"three"[..]).unwrap();
+            storage.root()
+        };
+
+        let storage = FileSystemStorage::open(directory.path(), TreeFormat::V3).unwrap();
+
+        assert_eq!(root, storage.root());
+        assert_eq!(vec![1, 2], storage.list());
+        assert_eq!(Some(b"three".to_vec()), read_file(&storage, 1));
+    }
+
+    #[test]
+    fn rebuild_without_a_snapshot() {
+        let directory = tempdir().unwrap();
+
+        let root = {
+            let mut storage = FileSystemStorage::open(directory.path(), TreeFormat::V3).unwrap();
+            storage.put(1, &mut &b"one"[..]).unwrap();
+            storage.root()
+        };
+
+        fs::remove_file(directory.path().join(SNAPSHOT)).unwrap();
+        fs::remove_dir_all(directory.path().join(CHUNKS)).unwrap();
+
+        let storage = FileSystemStorage::open(directory.path(), TreeFormat::V3).unwrap();
+
+        assert_eq!(root, storage.root());
+        assert!(storage.chunk_proof(1, 0, 1).unwrap().is_some());
+    }
+
+    #[test]
+    fn delete_then_reopen() {
+        let directory = tempdir().unwrap();
+
+        let empty = {
+            let mut storage = FileSystemStorage::open(directory.path(), TreeFormat::V3).unwrap();
+            let empty = storage.root();
+
+            storage.put(1, &mut &b"one"[..]).unwrap();
+            assert!(storage.delete(1).unwrap().is_some());
+            assert!(storage.delete(1).unwrap().is_none());
+
+            empty
+        };
+
+        let storage = FileSystemStorage::open(directory.path(), TreeFormat::V3).unwrap();
+
+        assert_eq!(empty, storage.root());
+        assert!(storage.list().is_empty());
+        assert_eq!(None, read_file(&storage, 1));
+    }
+
+    #[test]
+    fn remove_temporary_files() {
+        let directory = tempdir().unwrap();
+        fs::write(directory.path().join("1.tmp"), b"half written").unwrap();
+
+        let storage = FileSystemStorage::open(directory.path(), TreeFormat::V3).unwrap();
+
+        assert!(!directory.path().join("1.tmp").exists());
+        assert!(storage.list().is_empty());
+    }
+
+    #[test]
+    fn only_take_canonical_names() {
+        let directory = tempdir().unwrap();
+
+        for name in &["05", "+5", "5 ", "x"] {
+            fs::write(directory.path().join(name), b"not a file").unwrap();
+        }
+
+        let storage = FileSystemStorage::open(directory.path(), TreeFormat::V3).unwrap();
+
+        assert!(storage.list().is_empty());
+        assert_eq!(MerkleTree::new(FILE_ID_BITS, TreeFormat::V3).root(), storage.root());
+    }
+}
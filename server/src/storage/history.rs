@@ -0,0 +1,429 @@
+
+use file_hash::{
+    ChunkTree,
+    ConsistencyProof,
+    Hash,
+    LeafChange,
+    MerkleTree
+};
+
+use std::{
+    fs::{
+        self,
+        File,
+        OpenOptions
+    },
+    io::{
+        self,
+        Read,
+        Write
+    },
+    path::{
+        Path,
+        PathBuf
+    }
+};
+
+use super::{
+    Storage,
+    FileID,
+    Staged,
+    Stager,
+    sync_directory
+};
+
+use protocol::{
+    from_big_endian,
+    to_big_endian
+};
+
+/// Records every change made to another storage, so that the server can prove
+/// how an earlier root hash led to the current one.
+///
+/// If a log file is used, every change is appended to it before it is made, as
+/// a record holding its length as a big-endian 64-bit integer, followed by the
+/// root hash before the change, the encoded `LeafChange` and the signature of
+/// the change, if it was signed.
+///
+/// Only the latest `HISTORY_LIMIT` changes are certain to be kept. Clients
+/// whose root hash is older than that can't catch up.
+pub struct History {
+    storage: Box<Storage + Send + Sync>,
+
+    /// The root hash before every change, the change itself and its signature
+    changes: Vec<(Hash, LeafChange, Option<Vec<u8>>)>,
+
+    log: Option<Log>
+}
+
+struct Log {
+    path: PathBuf,
+    file: File
+}
+
+
+/// The number of changes kept once the history is compacted. It is compacted
+/// whenever it holds twice as many, so that the log is rarely rewritten.
+const HISTORY_LIMIT: usize = 10_000;
+
+
+impl History {
+    /// Record the changes to a storage in memory only
+    pub fn new(storage: Box<Storage + Send + Sync>) -> History {
+        History {
+            storage,
+            changes: Vec::new(),
+            log: None
+        }
+    }
+
+
+    /// Record the changes to a storage in a log file, creating it if needed,
+    /// and load all changes recorded before.
+    ///
+    /// If the server stopped after recording a change but before making it,
+    /// the change is discarded. If the recorded changes still do not lead to
+    /// the storage's current root hash, the history can't be proven and is
+    /// discarded as well.
+    pub fn open<P: AsRef<Path>>(storage: Box<Storage + Send + Sync>, path: P) -> io::Result<History> {
+        let path = path.as_ref();
+
+        if let Some(directory) = path.parent() {
+            fs::create_dir_all(directory)?;
+        }
+
+        let mut log = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(path)?;
+
+        let mut bytes = Vec::new();
+        log.read_to_end(&mut bytes)?;
+
+        let mut changes = Vec::new();
+        let mut length = 0;
+        let mut last_length = 0;
+
+        while let Some((change, record_length)) = read_record(&bytes[length..]) {
+            changes.push(change);
+            length += record_length;
+            last_length = record_length;
+        }
+
+        let root = storage.root();
+
+        if !is_chain(&changes, &root) && !changes.is_empty() && is_chain(&changes[..changes.len() - 1], &root) {
+            changes.pop();
+            length -= last_length;
+        }
+
+        if !is_chain(&changes, &root) {
+            changes.clear();
+            length = 0;
+        }
+
+        // Remove a partial record, or a history that can't be proven
+        if length < bytes.len() {
+            log.set_len(length as u64)?;
+            log.sync_all()?;
+        }
+
+        Ok(History {
+            storage,
+            changes,
+            log: Some(Log {
+                path: path.to_path_buf(),
+                file: log
+            })
+        })
+    }
+
+
+    /// Return the changes made since the tree had some root hash, or `None` if
+    /// the root hash is not part of the recorded history
+    pub fn since(&self, root: &Hash) -> Option<ConsistencyProof> {
+        self.start(root)
+            .map(|start| ConsistencyProof::new(
+                self.changes[start..].iter()
+                    .map(|&(_, ref change, _)| change.clone())
+                    .collect()
+            ))
+    }
+
+
+    /// Return the signatures of the changes returned by `since`, in the same
+    /// order. Changes made without a signature have none.
+    pub fn signatures_since(&self, root: &Hash) -> Option<Vec<Option<&[u8]>>> {
+        self.start(root)
+            .map(|start| self.changes[start..].iter()
+                .map(|&(_, _, ref signature)| signature.as_ref().map(|signature| &signature[..]))
+                .collect()
+            )
+    }
+
+
+    /// Store a staged file like `Storage::put_staged`, and keep the signature
+    /// of the change
+    pub fn put_signed(&mut self, file: FileID, staged: Staged, signature: Option<Vec<u8>>) -> io::Result<Option<Hash>> {
+        let new = staged.hash.clone();
+        let (root, change) = self.before_change(file, new);
+
+        let logged = self.log_change(&root, &change, &signature)?;
+
+        let result = self.storage.put_staged(file, staged);
+        self.finish_change(root, change, signature, logged);
+
+        result
+    }
+
+
+    /// Remove a file like `Storage::delete`, and keep the signature of the change
+    pub fn delete_signed(&mut self, file: FileID, signature: Option<Vec<u8>>) -> io::Result<Option<Hash>> {
+        if self.storage.tree().get(file as u64).is_err() {
+            return Ok(None);
+        }
+
+        let empty = self.storage.tree().format().empty_leaf();
+        let (root, change) = self.before_change(file, empty);
+
+        let logged = self.log_change(&root, &change, &signature)?;
+
+        let result = self.storage.delete(file);
+        self.finish_change(root, change, signature, logged);
+
+        result
+    }
+
+
+    /// Return the index of the first change made since the tree had some root hash
+    fn start(&self, root: &Hash) -> Option<usize> {
+        if *root == self.storage.root() {
+            return Some(self.changes.len());
+        }
+
+        // The latest occurrence gives the shortest proof
+        self.changes.iter()
+            .rposition(|&(ref before, _, _)| before == root)
+    }
+
+
+    /// The root hash and the change of a file to a new leaf, taken before the
+    /// file is changed
+    fn before_change(&self, file: FileID, new: Hash) -> (Hash, LeafChange) {
+        let tree = self.storage.tree();
+
+        let change = LeafChange {
+            proof: tree.proof(file as u64).unwrap(),
+            old: tree.get(file as u64).unwrap_or(tree.format().empty_leaf()),
+            new
+        };
+
+        (tree.root(), change)
+    }
+
+
+    /// Append a change to the log before it is made, returning the length of
+    /// the log before the change. If the change can't be logged it must not be
+    /// made, since it could not be proven after a restart.
+    fn log_change(&mut self, root: &Hash, change: &LeafChange, signature: &Option<Vec<u8>>) -> io::Result<u64> {
+        let log = match self.log {
+            Some(ref mut log) => log,
+            None => return Ok(0)
+        };
+
+        let length = log.file.metadata()?.len();
+
+        let written = log.file.write_all(&encode_record(root, change, signature))
+            .and_then(|_| log.file.sync_data());
+
+        if let Err(e) = written {
+            // Don't leave a partial record in front of the next one
+            let _ = log.file.set_len(length);
+            return Err(e);
+        }
+
+        Ok(length)
+    }
+
+    /// Remove the last change from the log, if it couldn't be made after all
+    fn unlog_change(&mut self, length: u64) {
+        if let Some(ref mut log) = self.log {
+            // If this fails too, the change is discarded when the log is
+            // next opened, since it does not lead to the current root hash
+            let _ = log.file.set_len(length)
+                .and_then(|_| log.file.sync_data());
+        }
+    }
+
+
+    /// Remember a logged change if the storage made it, even if it failed
+    /// afterwards, or remove it from the log if it was not made
+    fn finish_change(&mut self, root: Hash, change: LeafChange, signature: Option<Vec<u8>>, logged: u64) {
+        if self.storage.root() == root && change.old != change.new {
+            self.unlog_change(logged);
+        } else {
+            self.record(root, change, signature);
+        }
+    }
+
+    /// Remember a change once it has been logged and made to the storage
+    fn record(&mut self, root: Hash, change: LeafChange, signature: Option<Vec<u8>>) {
+        self.changes.push((root, change, signature));
+
+        // The change has been made, so if the log can't be compacted it is
+        // only longer than it needs to be, and compacting is tried again with
+        // the next change
+        if self.changes.len() >= 2 * HISTORY_LIMIT {
+            let _ = self.compact();
+        }
+    }
+
+    /// Forget all but the latest `HISTORY_LIMIT` changes, and rewrite the log
+    /// to hold only those
+    fn compact(&mut self) -> io::Result<()> {
+        let forgotten = self.changes.len().saturating_sub(HISTORY_LIMIT);
+
+        if let Some(ref mut log) = self.log {
+            let bytes: Vec<u8> = self.changes[forgotten..].iter()
+                .flat_map(|&(ref root, ref change, ref signature)| encode_record(root, change, signature))
+                .collect();
+
+            log.rewrite(&bytes)?;
+        }
+
+        self.changes.drain(..forgotten);
+        Ok(())
+    }
+}
+
+
+impl Log {
+    /// Replace the whole log in a way that either fully succeeds or leaves the
+    /// old log untouched
+    fn rewrite(&mut self, bytes: &[u8]) -> io::Result<()> {
+        let temporary = self.path.with_extension("tmp");
+
+        {
+            let mut file = File::create(&temporary)?;
+            file.write_all(bytes)?;
+            file.sync_all()?;
+        }
+
+        fs::rename(&temporary, &self.path)?;
+
+        if let Some(directory) = self.path.parent() {
+            sync_directory(directory)?;
+        }
+
+        self.file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .open(&self.path)?;
+
+        Ok(())
+    }
+}
+
+
+impl Storage for History {
+    fn get(&self, file: FileID) -> io::Result<Option<Box<Read + Send>>> {
+        self.storage.get(file)
+    }
+
+
+    fn size(&self, file: FileID) -> io::Result<Option<u64>> {
+        self.storage.size(file)
+    }
+
+
+    fn get_range(&self, file: FileID, offset: u64, length: u64) -> io::Result<Option<Box<Read + Send>>> {
+        self.storage.get_range(file, offset, length)
+    }
+
+
+    fn stager(&self) -> Stager {
+        self.storage.stager()
+    }
+
+
+    fn put_staged(&mut self, file: FileID, staged: Staged) -> io::Result<Option<Hash>> {
+        self.put_signed(file, staged, None)
+    }
+
+
+    fn delete(&mut self, file: FileID) -> io::Result<Option<Hash>> {
+        self.delete_signed(file, None)
+    }
+
+
+    fn list(&self) -> Vec<FileID> {
+        self.storage.list()
+    }
+
+
+    fn tree(&self) -> &MerkleTree {
+        self.storage.tree()
+    }
+
+
+    fn chunk_tree(&self, file: FileID) -> io::Result<Option<ChunkTree>> {
+        self.storage.chunk_tree(file)
+    }
+}
+
+
+/// Encode a change as a record of the log
+fn encode_record(root: &Hash, change: &LeafChange, signature: &Option<Vec<u8>>) -> Vec<u8> {
+    let mut bytes = root.as_bytes().to_vec();
+    bytes.extend_from_slice(&change.to_bytes());
+
+    if let Some(ref signature) = *signature {
+        bytes.extend_from_slice(signature);
+    }
+
+    let mut record = to_big_endian(bytes.len() as u64, 8);
+    record.extend_from_slice(&bytes);
+    record
+}
+
+/// Read the record at the start of some bytes, returning the root hash before
+/// the change, the change, its signature and the length of the record. Returns
+/// `None` if the bytes don't start with a complete record.
+fn read_record(bytes: &[u8]) -> Option<((Hash, LeafChange, Option<Vec<u8>>), usize)> {
+    if bytes.len() < 8 {
+        return None;
+    }
+
+    let length = from_big_endian(&bytes[..8]);
+    if length < Hash::BYTES as u64 || length > (bytes.len() - 8) as u64 {
+        return None;
+    }
+
+    let record = &bytes[8..8 + length as usize];
+    let root = Hash::from_bytes(&record[..Hash::BYTES]);
+    let (change, rest) = LeafChange::from_prefix(&record[Hash::BYTES..]).ok()?;
+
+    // Changes recorded before they could be signed end with the change
+    let signature = if rest.is_empty() {
+        None
+    } else {
+        Some(rest.to_vec())
+    };
+
+    Some(((root, change, signature), 8 + length as usize))
+}
+
+
+/// Check that every change leads to the root hash before the next one, and
+/// the last change to the current root hash
+fn is_chain(changes: &[(Hash, LeafChange, Option<Vec<u8>>)], current: &Hash) -> bool {
+    let roots = changes.iter()
+        .skip(1)
+        .map(|&(ref root, _, _)| root)
+        .chain(Some(current));
+
+    changes.iter()
+        .zip(roots)
+        .all(|(&(ref before, ref change, _), after)| change.apply(before).ok().as_ref() == Some(after))
+}
@@ -0,0 +1,403 @@
+
+use file_hash::{
+    ChunkTree,
+    Hash,
+    MerkleTree,
+    TreeFormat,
+    FILE_ID_BITS
+};
+
+use std::{
+    collections::HashMap,
+    fs::{
+        self,
+        File,
+        OpenOptions
+    },
+    io::{
+        self,
+        BufReader,
+        BufWriter,
+        Read,
+        Seek,
+        SeekFrom,
+        Write
+    },
+    path::{
+        Path,
+        PathBuf
+    }
+};
+
+use super::{
+    Storage,
+    FileID,
+    Staged,
+    Stager,
+    copy_leaf,
+    kept_chunk_tree
+};
+
+use protocol::{
+    from_big_endian,
+    to_big_endian
+};
+
+/// Stores every change as a record appended to a single log file.
+///
+/// A record starts with a byte telling its kind, followed by the file ID and
+/// the length of the data as big-endian 64-bit integers, and then the data
+/// itself. Records are never modified once written.
+pub struct LogStorage {
+    path: PathBuf,
+    log: File,
+
+    /// The length of the log, in bytes
+    length: u64,
+
+    /// The location of the latest data of every file in the log
+    index: HashMap<FileID, Location>,
+
+    /// The hashes of every file's chunks, if the format splits files into chunks
+    chunks: HashMap<FileID, Vec<Hash>>,
+
+    hashes: MerkleTree,
+
+    /// Stages files in the log's directory, since the length of a record is
+    /// written before its data and has to be known first
+    stager: Stager
+}
+
+#[derive(Clone, Copy)]
+struct Location {
+    offset: u64,
+    length: u64
+}
+
+const PUT: u8 = 0;
+const DELETE: u8 = 1;
+
+const HEADER_BYTES: u64 = 17;
+
+
+impl LogStorage {
+    /// Open a log file, creating it if needed, and replay all its records
+    pub fn open<P: AsRef<Path>>(path: P, format: TreeFormat) -> io::Result<LogStorage> {
+        let path = path.as_ref().to_path_buf();
+        let directory = path.parent().unwrap_or(Path::new(".")).to_path_buf();
+        fs::create_dir_all(&directory)?;
+
+        let log = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(&path)?;
+
+        let size = log.metadata()?.len();
+
+        let mut length = 0;
+        let mut index = HashMap::new();
+        let mut chunks = HashMap::new();
+        let mut hashes = MerkleTree::new(FILE_ID_BITS, format);
+
+        {
+            let mut reader = BufReader::new(&log);
+
+            while let Some((kind, file, data_length, hash, file_chunks)) = read_record(&mut reader, size - length, format)? {
+                let location = Location {
+                    offset: length + HEADER_BYTES,
+                    length: data_length
+                };
+
+                length = location.offset + location.length;
+
+                if kind == PUT {
+                    hashes.insert(file as u64, hash).unwrap();
+                    index.insert(file, location);
+                    chunks.insert(file, file_chunks);
+                } else {
+                    hashes.remove(file as u64).unwrap();
+                    index.remove(&file);
+                    chunks.remove(&file);
+                }
+            }
+        }
+
+        // The server stopped in the middle of appending the last record
+        if length < size {
+            log.set_len(length)?;
+            log.sync_all()?;
+        }
+
+        Ok(LogStorage {
+            path,
+            log,
+            length,
+            index,
+            chunks,
+            hashes,
+            stager: Stager::open(&directory, format)?
+        })
+    }
+
+
+    /// Append a record with `length` bytes of data to the log, returning the
+    /// offset of its data
+    fn append(&mut self, kind: u8, file: FileID, length: u64, data: &mut Read) -> io::Result<u64> {
+        let mut header = Vec::with_capacity(HEADER_BYTES as usize);
+        header.push(kind);
+        header.extend_from_slice(&to_big_endian(file as u64, 8));
+        header.extend_from_slice(&to_big_endian(length, 8));
+
+        let written = {
+            let mut writer = BufWriter::new(&self.log);
+
+            writer.write_all(&header)
+                .and_then(|_| io::copy(&mut data.take(length), &mut writer))
+                .and_then(|copied| if copied == length {
+                    writer.flush()
+                } else {
+                    Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Data ended before the record"))
+                })
+        };
+
+        let written = written.and_then(|_| self.log.sync_data());
+
+        if let Err(e) = written {
+            // Don't leave a partial record in front of the next one
+            let _ = self.log.set_len(self.length);
+            return Err(e);
+        }
+
+        let offset = self.length + HEADER_BYTES;
+        self.length = offset + length;
+
+        Ok(offset)
+    }
+}
+
+impl Storage for LogStorage {
+    fn get(&self, file: FileID) -> io::Result<Option<Box<Read + Send>>> {
+        let location = match self.index.get(&file) {
+            Some(location) => *location,
+            None => return Ok(None)
+        };
+
+        // A separate handle, so that concurrent readers don't share a cursor
+        let mut log = File::open(&self.path)?;
+        log.seek(SeekFrom::Start(location.offset))?;
+
+        Ok(Some(Box::new(log.take(location.length))))
+    }
+
+
+    fn size(&self, file: FileID) -> io::Result<Option<u64>> {
+        Ok(self.index.get(&file).map(|location| location.length))
+    }
+
+
+    fn get_range(&self, file: FileID, offset: u64, length: u64) -> io::Result<Option<Box<Read + Send>>> {
+        let location = match self.index.get(&file) {
+            Some(location) => *location,
+            None => return Ok(None)
+        };
+
+        // Never read past the file into the next record
+        let offset = offset.min(location.length);
+        let length = length.min(location.length - offset);
+
+        let mut log = File::open(&self.path)?;
+        log.seek(SeekFrom::Start(location.offset + offset))?;
+
+        Ok(Some(Box::new(log.take(length))))
+    }
+
+
+    fn stager(&self) -> Stager {
+        self.stager.clone()
+    }
+
+
+    fn put_staged(&mut self, file: FileID, mut staged: Staged) -> io::Result<Option<Hash>> {
+        staged.file.seek(SeekFrom::Start(0))?;
+
+        let offset = self.append(PUT, file, staged.length, &mut staged.file)?;
+
+        self.index.insert(file, Location {
+            offset,
+            length: staged.length
+        });
+        self.chunks.insert(file, staged.chunks);
+
+        Ok(self.hashes.insert(file as u64, staged.hash).unwrap())
+    }
+
+
+    fn delete(&mut self, file: FileID) -> io::Result<Option<Hash>> {
+        if !self.index.contains_key(&file) {
+            return Ok(None);
+        }
+
+        self.append(DELETE, file, 0, &mut io::empty())?;
+        self.index.remove(&file);
+        self.chunks.remove(&file);
+
+        Ok(self.hashes.remove(file as u64).unwrap())
+    }
+
+
+    fn list(&self) -> Vec<FileID> {
+        let mut files: Vec<FileID> = self.index.keys().cloned().collect();
+        files.sort();
+        files
+    }
+
+
+    fn tree(&self) -> &MerkleTree {
+        &self.hashes
+    }
+
+
+    fn chunk_tree(&self, file: FileID) -> io::Result<Option<ChunkTree>> {
+        Ok(self.index.get(&file)
+            .and_then(|location| kept_chunk_tree(&self.hashes, file, location.length, &self.chunks[&file])))
+    }
+}
+
+
+/// Read the next record from the log, returning its kind, file, the length of
+/// its data, the hash of the data as a leaf and the hashes of its chunks. Returns `None` at the end of
+/// the log or if the remaining bytes don't make up a complete record.
+fn read_record<R: Read>(reader: &mut R, remaining: u64, format: TreeFormat) -> io::Result<Option<(u8, FileID, u64, Hash, Vec<Hash>)>> {
+    if remaining < HEADER_BYTES {
+        return Ok(None);
+    }
+
+    let mut header = [0; HEADER_BYTES as usize];
+    reader.read_exact(&mut header)?;
+
+    let kind = header[0];
+    let file = from_big_endian(&header[1..9]);
+    let length = from_big_endian(&header[9..17]);
+
+    if length > remaining - HEADER_BYTES {
+        return Ok(None);
+    }
+
+    if kind != PUT && kind != DELETE || file > FileID::max_value() as u64 {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "Corrupt record in log"));
+    }
+
+    let (hash, chunks, _) = copy_leaf(format, &mut reader.take(length), &mut io::sink())?;
+
+    Ok(Some((kind, file as FileID, length, hash, chunks)))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::{
+        read_file,
+        STAGING
+    };
+
+    use tempfile::tempdir;
+
+    #[test]
+    fn replay_the_log() {
+        let directory = tempdir().unwrap();
+        let path = directory.path().join("files.log");
+
+        let root = {
+            let mut storage = LogStorage::open(&path, TreeFormat::V3).unwrap();
+            storage.put(1, &mut &b"one"[..]).unwrap();
+            storage.put(2, &mut &b"two"[..]).unwrap();
+            storage.put(1, &mut &b"three"[..]).unwrap();
+            storage.root()
+        };
+
+        let storage = LogStorage::open(&path, TreeFormat::V3).unwrap();
+
+        assert_eq!(root, storage.root());
+        assert_eq!(vec![1, 2], storage.list());
+        assert_eq!(Some(b"three".to_vec()), read_file(&storage, 1));
+        assert_eq!(Some(b"two".to_vec()), read_file(&storage, 2));
+    }
+
+    #[test]
+    fn truncate_a_torn_record() {
+        let directory = tempdir().unwrap();
+        let path = directory.path().join("files.log");
+
+        let (root, length) = {
+            let mut storage = LogStorage::open(&path, TreeFormat::V3).unwrap();
+            storage.put(1, &mut &b"one"[..]).unwrap();
+            (storage.root(), storage.length)
+        };
+
+        // A record that says it holds 100 bytes, but ends after 4
+        {
+            let mut log = OpenOptions::new().append(true).open(&path).unwrap();
+            log.write_all(&[PUT]).unwrap();
+            log.write_all(&to_big_endian(2, 8)).unwrap();
+            log.write_all(&to_big_endian(100, 8)).unwrap();
+            log.write_all(b"torn").unwrap();
+        }
+
+        let mut storage = LogStorage::open(&path, TreeFormat::V3).unwrap();
+
+        assert_eq!(root, storage.root());
+        assert_eq!(length, fs::metadata(&path).unwrap().len());
+        assert_eq!(None, read_file(&storage, 2));
+
+        storage.put(2, &mut &b"two"[..]).unwrap();
+        let root = storage.root();
+        drop(storage);
+
+        let storage = LogStorage::open(&path, TreeFormat::V3).unwrap();
+        assert_eq!(root, storage.root());
+        assert_eq!(Some(b"two".to_vec()), read_file(&storage, 2));
+    }
+
+    #[test]
+    fn delete_then_reopen() {
+        let directory = tempdir().unwrap();
+        let path = directory.path().join("files.log");
+
+        let empty = {
+            let mut storage = LogStorage::open(&path, TreeFormat::V3).unwrap();
+            let empty = storage.root();
+
+            storage.put(1, &mut &b"one"[..]).unwrap();
+            assert!(storage.delete(1).unwrap().is_some());
+            assert!(storage.delete(1).unwrap().is_none());
+
+            empty
+        };
+
+        let storage = LogStorage::open(&path, TreeFormat::V3).unwrap();
+
+        assert_eq!(empty, storage.root());
+        assert!(storage.list().is_empty());
+        assert_eq!(None, read_file(&storage, 1));
+    }
+
+    #[test]
+    fn remove_staged_files() {
+        let directory = tempdir().unwrap();
+        let path = directory.path().join("files.log");
+
+        {
+            let storage = LogStorage::open(&path, TreeFormat::V3).unwrap();
+            let staged = storage.stager().stage(&mut &b"never stored"[..]).unwrap();
+
+            // As if the server stopped before the file was stored
+            staged.file.persist(directory.path().join(STAGING).join("left behind")).unwrap();
+        }
+
+        LogStorage::open(&path, TreeFormat::V3).unwrap();
+
+        assert_eq!(0, fs::read_dir(directory.path().join(STAGING)).unwrap().count());
+    }
+}
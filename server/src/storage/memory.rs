@@ -0,0 +1,143 @@
+
+use file_hash::{
+    ChunkTree,
+    Hash,
+    MerkleTree,
+    TreeFormat,
+    FILE_ID_BITS
+};
+
+use std::{
+    collections::HashMap,
+    io::{
+        self,
+        Cursor,
+        Read,
+        Seek,
+        SeekFrom
+    }
+};
+
+use super::{
+    Storage,
+    FileID,
+    Staged,
+    Stager,
+    kept_chunk_tree
+};
+
+/// Keeps every file in memory. Everything is lost when the server stops.
+pub struct MemoryStorage {
+    files: HashMap<FileID, Vec<u8>>,
+
+    /// The hashes of every file's chunks, if the format splits files into chunks
+    chunks: HashMap<FileID, Vec<Hash>>,
+
+    hashes: MerkleTree
+}
+
+impl MemoryStorage {
+    pub fn new(format: TreeFormat) -> MemoryStorage {
+        MemoryStorage {
+            files: HashMap::new(),
+            chunks: HashMap::new(),
+            hashes: MerkleTree::new(FILE_ID_BITS, format)
+        }
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn get(&self, file: FileID) -> io::Result<Option<Box<Read + Send>>> {
+        Ok(self.files.get(&file).map(|data| {
+            Box::new(Cursor::new(data.clone())) as Box<Read + Send>
+        }))
+    }
+
+
+    fn size(&self, file: FileID) -> io::Result<Option<u64>> {
+        Ok(self.files.get(&file).map(|data| data.len() as u64))
+    }
+
+
+    fn stager(&self) -> Stager {
+        Stager::temporary(self.hashes.format())
+    }
+
+
+    fn put_staged(&mut self, file: FileID, mut staged: Staged) -> io::Result<Option<Hash>> {
+        let mut contents = Vec::new();
+        staged.file.seek(SeekFrom::Start(0))?;
+        staged.file.read_to_end(&mut contents)?;
+
+        self.files.insert(file, contents);
+        self.chunks.insert(file, staged.chunks);
+
+        Ok(self.hashes.insert(file as u64, staged.hash).unwrap())
+    }
+
+
+    fn delete(&mut self, file: FileID) -> io::Result<Option<Hash>> {
+        self.files.remove(&file);
+        self.chunks.remove(&file);
+
+        Ok(self.hashes.remove(file as u64).unwrap())
+    }
+
+
+    fn list(&self) -> Vec<FileID> {
+        let mut files: Vec<FileID> = self.files.keys().cloned().collect();
+        files.sort();
+        files
+    }
+
+
+    fn tree(&self) -> &MerkleTree {
+        &self.hashes
+    }
+
+
+    fn chunk_tree(&self, file: FileID) -> io::Result<Option<ChunkTree>> {
+        Ok(self.files.get(&file)
+            .and_then(|data| kept_chunk_tree(&self.hashes, file, data.len() as u64, &self.chunks[&file])))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::read_file;
+
+    use file_hash::CHUNK_SIZE;
+
+    #[test]
+    fn put_and_delete() {
+        let mut storage = MemoryStorage::new(TreeFormat::V3);
+        let empty = storage.root();
+
+        assert_eq!(None, storage.put(1, &mut &b"one"[..]).unwrap());
+        assert_eq!(Some(TreeFormat::V3.leaf(b"one")), storage.put(1, &mut &b"two"[..]).unwrap());
+        assert_eq!(Some(b"two".to_vec()), read_file(&storage, 1));
+        assert_eq!(Some(3), storage.size(1).unwrap());
+        assert_eq!(vec![1], storage.list());
+
+        assert_eq!(Some(TreeFormat::V3.leaf(b"two")), storage.delete(1).unwrap());
+        assert_eq!(None, storage.delete(1).unwrap());
+        assert_eq!(None, read_file(&storage, 1));
+        assert_eq!(empty, storage.root());
+    }
+
+    #[test]
+    fn prove_chunks_from_kept_hashes() {
+        let mut storage = MemoryStorage::new(TreeFormat::V3);
+        let file = vec![7; 3 * CHUNK_SIZE as usize];
+        storage.put(1, &mut &file[..]).unwrap();
+
+        let proof = storage.chunk_proof(1, 1, 1).unwrap().unwrap();
+        let chunk = &file[CHUNK_SIZE as usize..2 * CHUNK_SIZE as usize];
+
+        assert!(proof.verify(&[chunk], &storage.tree().get(1).unwrap()).is_ok());
+        assert!(storage.chunk_proof(1, 3, 1).unwrap().is_none());
+        assert!(storage.chunk_proof(2, 0, 1).unwrap().is_none());
+    }
+}
@@ -0,0 +1,357 @@
+
+mod memory;
+mod filesystem;
+mod log;
+mod history;
+
+pub use self::memory::MemoryStorage;
+pub use self::filesystem::FileSystemStorage;
+pub use self::log::LogStorage;
+pub use self::history::History;
+
+use file_hash::{
+    ChunkProof,
+    ChunkTree,
+    Hash,
+    MerkleProof,
+    MerkleTree,
+    MultiProof,
+    TreeFormat,
+    chunk_count
+};
+
+use std::{
+    env,
+    fs::{
+        self,
+        File
+    },
+    io::{
+        self,
+        Read,
+        Write
+    },
+    path::{
+        Path,
+        PathBuf
+    }
+};
+
+use tempfile::NamedTempFile;
+
+pub use protocol::FileID;
+
+
+/// Stores files together with a Merkle tree of their hashes
+pub trait Storage {
+    /// Return a reader of the contents of a file, if present
+    fn get(&self, file: FileID) -> io::Result<Option<Box<Read + Send>>>;
+
+    /// Return the length of a file in bytes, if present
+    fn size(&self, file: FileID) -> io::Result<Option<u64>> {
+        match self.get(file)? {
+            Some(mut data) => io::copy(&mut data, &mut io::sink()).map(Some),
+            None => Ok(None)
+        }
+    }
+
+    /// Return a reader of up to `length` bytes of a file, starting at `offset`,
+    /// if the file is present
+    fn get_range(&self, file: FileID, offset: u64, length: u64) -> io::Result<Option<Box<Read + Send>>> {
+        match self.get(file)? {
+            Some(mut data) => {
+                io::copy(&mut (&mut data).take(offset), &mut io::sink())?;
+                Ok(Some(Box::new(data.take(length))))
+            }
+
+            None => Ok(None)
+        }
+    }
+
+    /// Store everything read from `data` as a file, returning the hash of the
+    /// file it replaced, if any
+    fn put(&mut self, file: FileID, data: &mut Read) -> io::Result<Option<Hash>> {
+        let staged = self.stager().stage(data)?;
+        self.put_staged(file, staged)
+    }
+
+    /// Return a `Stager` whose files can be moved into the storage
+    fn stager(&self) -> Stager;
+
+    /// Store a staged file, returning the hash of the file it replaced, if any
+    fn put_staged(&mut self, file: FileID, staged: Staged) -> io::Result<Option<Hash>>;
+
+    /// Remove a file, returning its hash, if it was present
+    fn delete(&mut self, file: FileID) -> io::Result<Option<Hash>>;
+
+    /// Return the IDs of all stored files in ascending order
+    fn list(&self) -> Vec<FileID>;
+
+    /// Return the tree containing the hash of every stored file
+    fn tree(&self) -> &MerkleTree;
+
+
+    /// Return the root hash of the tree
+    fn root(&self) -> Hash {
+        self.tree().root()
+    }
+
+    /// Return the hashes required to reconstruct the root hash from a file
+    fn proof(&self, file: FileID) -> Option<MerkleProof> {
+        self.tree().proof(file as u64).ok()
+    }
+
+    /// Return the hashes required to reconstruct the root hash from an empty
+    /// slot, or `None` if the file is present
+    fn absence_proof(&self, file: FileID) -> Option<MerkleProof> {
+        self.tree().absence_proof(file as u64).ok()
+    }
+
+    /// Return the hashes required to reconstruct the root hash from several files at once
+    fn multi_proof(&self, files: &[FileID]) -> Option<MultiProof> {
+        let indices: Vec<u64> = files.iter().map(|&file| file as u64).collect();
+        self.tree().multi_proof(&indices).ok()
+    }
+
+    /// Return the tree of a file's chunks, or `None` if the file is missing or
+    /// the tree's format does not split files into chunks. Reads the whole
+    /// file, unless the storage kept the hashes of its chunks.
+    fn chunk_tree(&self, file: FileID) -> io::Result<Option<ChunkTree>> {
+        let format = self.tree().format();
+
+        if !format.supports_chunk_proofs() {
+            return Ok(None);
+        }
+
+        match self.get(file)? {
+            Some(mut data) => ChunkTree::read(format, &mut data).map(Some),
+            None => Ok(None)
+        }
+    }
+
+    /// Return the hashes required to reconstruct the leaf of a file from a run
+    /// of its chunks, or `None` if the file or the chunks are missing, or the
+    /// tree's format does not split files into chunks
+    fn chunk_proof(&self, file: FileID, first: u64, count: u64) -> io::Result<Option<ChunkProof>> {
+        Ok(self.chunk_tree(file)?.and_then(|tree| tree.proof(first, count)))
+    }
+}
+
+
+/// Receives files before they are stored, so that a storage is only changed
+/// once a file has been received in full. Staging doesn't touch the storage
+/// itself, so it needs no lock.
+#[derive(Clone)]
+pub struct Stager {
+    directory: PathBuf,
+    format: TreeFormat
+}
+
+/// A file that has been received and hashed, but not yet stored
+pub struct Staged {
+    file: NamedTempFile,
+    hash: Hash,
+    chunks: Vec<Hash>,
+    length: u64
+}
+
+
+/// The name of the directory files are staged in, next to the stored files
+const STAGING: &'static str = "staging";
+
+impl Stager {
+    /// Stage files in a directory next to a storage's, removing any files left
+    /// behind by uploads that never completed
+    fn open(directory: &Path, format: TreeFormat) -> io::Result<Stager> {
+        let directory = directory.join(STAGING);
+
+        match fs::remove_dir_all(&directory) {
+            Ok(_) => {}
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e)
+        }
+
+        fs::create_dir_all(&directory)?;
+
+        Ok(Stager {
+            directory,
+            format
+        })
+    }
+
+    /// Stage files in the system's temporary directory, for storages that
+    /// don't keep files on disk
+    fn temporary(format: TreeFormat) -> Stager {
+        Stager {
+            directory: env::temp_dir(),
+            format
+        }
+    }
+
+
+    /// Write everything read from `data` to disk, hashing it on the way
+    pub fn stage(&self, data: &mut Read) -> io::Result<Staged> {
+        let mut file = NamedTempFile::new_in(&self.directory)?;
+
+        let (hash, chunks, length) = copy_leaf(self.format, data, &mut file)?;
+        file.as_file().sync_all()?;
+
+        Ok(Staged {
+            file,
+            hash,
+            chunks,
+            length
+        })
+    }
+}
+
+impl Staged {
+    /// Returns the length of the file in bytes
+    pub fn length(&self) -> u64 {
+        self.length
+    }
+}
+
+
+/// Open one of the storage backends by name, keeping any data in a directory.
+/// The history of the backend's root hash is kept in the same place as its files.
+///
+/// The tree format is kept in the directory too. A new directory takes the
+/// configured format, or the default; fails if a format other than the one the
+/// directory was created with is configured.
+pub fn open(backend: &str, directory: &Path, format: Option<TreeFormat>) -> io::Result<History> {
+    match backend {
+        "memory" => Ok(History::new(Box::new(MemoryStorage::new(format.unwrap_or_default())))),
+
+        "filesystem" => History::open(
+            Box::new(FileSystemStorage::open(directory, stored_format(directory, format)?)?),
+            directory.join("history.log")
+        ),
+
+        "log" => History::open(
+            Box::new(LogStorage::open(directory.join("files.log"), stored_format(directory, format)?)?),
+            directory.join("history.log")
+        ),
+
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Unknown storage backend: {:?}", backend)
+        ))
+    }
+}
+
+
+/// Where the version of the tree format is kept, as a single byte
+const FORMAT_PATH: &'static str = "tree_format";
+
+/// The format of directories created before their format was kept in them
+const LEGACY_FORMAT: TreeFormat = TreeFormat::V2;
+
+/// Return the format of the tree kept in a directory, recording it if the
+/// directory has none yet
+fn stored_format(directory: &Path, configured: Option<TreeFormat>) -> io::Result<TreeFormat> {
+    let path = directory.join(FORMAT_PATH);
+
+    let stored = match fs::read(&path) {
+        Ok(bytes) => match bytes[..] {
+            [version] => TreeFormat::from_version(version),
+            _ => None
+        }.map(Some).ok_or_else(|| io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{} does not hold a known tree format", path.display())
+        ))?,
+
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => None,
+        Err(e) => return Err(e)
+    };
+
+    let format = match (stored, configured) {
+        (Some(stored), Some(configured)) if stored != configured => return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("The data in {} is in tree format {}, but tree format {} is configured",
+                directory.display(), stored.version(), configured.version())
+        )),
+
+        (Some(stored), _) => return Ok(stored),
+        (None, Some(configured)) => configured,
+        (None, None) if is_empty(directory)? => TreeFormat::default(),
+        (None, None) => LEGACY_FORMAT
+    };
+
+    fs::create_dir_all(directory)?;
+
+    let mut file = File::create(&path)?;
+    file.write_all(&[format.version()])?;
+    file.sync_all()?;
+
+    Ok(format)
+}
+
+/// Renames and removals are only durable once the directory itself has been synced
+fn sync_directory(directory: &Path) -> io::Result<()> {
+    File::open(directory)?.sync_all()
+}
+
+/// Returns true if a directory is missing or holds nothing
+fn is_empty(directory: &Path) -> io::Result<bool> {
+    match fs::read_dir(directory) {
+        Ok(mut entries) => Ok(entries.next().is_none()),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(true),
+        Err(e) => Err(e)
+    }
+}
+
+
+/// Copy everything from a reader to a writer, a part at a time. Returns the
+/// hash of the data as a leaf, the hashes of its chunks if the format splits
+/// files into chunks, and the number of bytes copied.
+fn copy_leaf(format: TreeFormat, reader: &mut Read, writer: &mut Write) -> io::Result<(Hash, Vec<Hash>, u64)> {
+    let mut hasher = format.leaf_hasher();
+    let mut buffer = vec![0; 64 * 1024];
+    let mut length = 0;
+
+    loop {
+        let count = match reader.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(count) => count,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e)
+        };
+
+        hasher.update(&buffer[..count]);
+        writer.write_all(&buffer[..count])?;
+        length += count as u64;
+    }
+
+    let (hash, chunks) = hasher.finish_with_chunks();
+
+    Ok((hash, chunks, length))
+}
+
+
+/// Read the whole of a file, if present
+#[cfg(test)]
+fn read_file(storage: &Storage, file: FileID) -> Option<Vec<u8>> {
+    storage.get(file).unwrap().map(|mut data| {
+        let mut contents = Vec::new();
+        data.read_to_end(&mut contents).unwrap();
+        contents
+    })
+}
+
+
+/// Rebuild the tree of a file's chunks from the hashes kept for them. Returns
+/// `None` unless they make up the leaf of the file in a tree.
+fn kept_chunk_tree(tree: &MerkleTree, file: FileID, length: u64, chunks: &[Hash]) -> Option<ChunkTree> {
+    if chunks.len() as u64 != chunk_count(length) {
+        return None;
+    }
+
+    let chunk_tree = ChunkTree::new(tree.format(), length, chunks);
+
+    if tree.get(file as u64).ok() == Some(chunk_tree.leaf()) {
+        Some(chunk_tree)
+    } else {
+        None
+    }
+}